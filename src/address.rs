@@ -0,0 +1,524 @@
+//! RFC 5322 mailboxes and address lists.
+
+use std::str::FromStr;
+
+use super::rfc2047;
+
+////////////////////////////////////////////// Mailbox /////////////////////////////////////////////
+
+/// A single address with an optional display name, e.g. `"Doe, Jane" <jane@example.org>`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Mailbox {
+    pub name: Option<String>,
+    pub address: String,
+}
+
+impl Mailbox {
+    /// Create a new mailbox without a display name.
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            name: None,
+            address: address.into(),
+        }
+    }
+
+    /// The local part of the address, i.e. everything before the last `@`.
+    pub fn local_part(&self) -> &str {
+        self.address
+            .rsplit_once('@')
+            .map(|(local, _)| local)
+            .unwrap_or(&self.address)
+    }
+
+    /// The domain of the address, i.e. everything after the last `@`.
+    pub fn domain(&self) -> Option<&str> {
+        self.address.rsplit_once('@').map(|(_, domain)| domain)
+    }
+
+    /// True if this mailbox has the address `address`, ignoring case.
+    pub fn is(&self, address: &str) -> bool {
+        self.address.eq_ignore_ascii_case(address.trim())
+    }
+
+    /// Write the mailbox as it should appear in a header:  pure ASCII, with non-ASCII display names
+    /// as RFC 2047 encoded words.
+    pub fn encoded(&self) -> String {
+        match &self.name {
+            Some(name) if !name.is_ascii() => {
+                format!("{} <{}>", rfc2047::encode_phrase(name), self.address)
+            }
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl FromStr for Mailbox {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let list = AddressList::from_str(s)?;
+        let mut mailboxes = list.mailboxes();
+        match (mailboxes.next(), mailboxes.next()) {
+            (Some(mailbox), None) => Ok(mailbox.clone()),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("expected exactly one mailbox: {:?}", s),
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Mailbox {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) if !name.is_empty() => {
+                write!(f, "{} <{}>", quote_phrase(name), self.address)
+            }
+            _ => write!(f, "{}", self.address),
+        }
+    }
+}
+
+////////////////////////////////////////////// Address /////////////////////////////////////////////
+
+/// One entry of an address list:  a mailbox or a named group of mailboxes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Address {
+    Mailbox(Mailbox),
+    Group(String, Vec<Mailbox>),
+}
+
+impl Address {
+    fn encoded(&self) -> String {
+        match self {
+            Address::Mailbox(mailbox) => mailbox.encoded(),
+            Address::Group(name, members) => format!(
+                "{}: {};",
+                if name.is_ascii() {
+                    quote_phrase(name)
+                } else {
+                    rfc2047::encode_phrase(name)
+                },
+                members
+                    .iter()
+                    .map(Mailbox::encoded)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Mailbox(mailbox) => write!(f, "{}", mailbox),
+            Address::Group(name, members) => write!(
+                f,
+                "{}: {};",
+                quote_phrase(name),
+                members
+                    .iter()
+                    .map(Mailbox::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+//////////////////////////////////////////// AddressList ///////////////////////////////////////////
+
+/// The value of an address header such as To or Cc.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AddressList {
+    pub addresses: Vec<Address>,
+}
+
+impl AddressList {
+    /// Every mailbox in the list, including the members of groups.
+    pub fn mailboxes(&self) -> impl Iterator<Item = &Mailbox> {
+        self.addresses.iter().flat_map(|address| match address {
+            Address::Mailbox(mailbox) => std::slice::from_ref(mailbox).iter(),
+            Address::Group(_, members) => members.iter(),
+        })
+    }
+
+    /// True if the list contains no mailboxes.
+    pub fn is_empty(&self) -> bool {
+        self.mailboxes().next().is_none()
+    }
+
+    /// True if any mailbox in the list has the address `address`, ignoring case.
+    pub fn contains(&self, address: &str) -> bool {
+        self.mailboxes().any(|mailbox| mailbox.is(address))
+    }
+
+    /// Append the addresses of `other` to this list.
+    pub fn extend(&mut self, other: AddressList) {
+        self.addresses.extend(other.addresses);
+    }
+
+    /// Keep only the mailboxes for which `f` returns true.  Groups keep their names even if they
+    /// lose all their members.
+    pub fn retain(&mut self, mut f: impl FnMut(&Mailbox) -> bool) {
+        self.addresses.retain_mut(|address| match address {
+            Address::Mailbox(mailbox) => f(mailbox),
+            Address::Group(_, members) => {
+                members.retain(&mut f);
+                true
+            }
+        });
+    }
+
+    /// Drop every mailbox whose address appeared earlier in the list.
+    pub fn dedup(&mut self) {
+        let mut seen = std::collections::HashSet::new();
+        self.retain(|mailbox| seen.insert(mailbox.address.to_ascii_lowercase()));
+    }
+
+    /// Write the list as it should appear in a header:  pure ASCII, with non-ASCII display names
+    /// as RFC 2047 encoded words.
+    pub fn encoded(&self) -> String {
+        self.addresses
+            .iter()
+            .map(Address::encoded)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl FromStr for AddressList {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s)?;
+        let mut addresses = vec![];
+        let mut tokens = tokens.into_iter().peekable();
+        while tokens.peek().is_some() {
+            let (phrase, terminator) = parse_mailbox_tokens(&mut tokens);
+            match terminator {
+                Some(Token::Colon) => {
+                    let name = phrase_text(&phrase);
+                    let mut members = vec![];
+                    loop {
+                        let (member, terminator) = parse_mailbox_tokens(&mut tokens);
+                        if let Some(mailbox) = to_mailbox(member) {
+                            members.push(mailbox);
+                        }
+                        match terminator {
+                            Some(Token::Semicolon) | None => break,
+                            _ => {}
+                        }
+                    }
+                    addresses.push(Address::Group(name, members));
+                    // Consume the separator after a group.
+                    if let Some(Token::Comma) = tokens.peek() {
+                        tokens.next();
+                    }
+                }
+                _ => {
+                    if let Some(mailbox) = to_mailbox(phrase) {
+                        addresses.push(Address::Mailbox(mailbox));
+                    }
+                }
+            }
+        }
+        Ok(Self { addresses })
+    }
+}
+
+impl std::fmt::Display for AddressList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let addresses = self
+            .addresses
+            .iter()
+            .map(Address::to_string)
+            .collect::<Vec<_>>();
+        write!(f, "{}", addresses.join(", "))
+    }
+}
+
+////////////////////////////////////////////// parsing /////////////////////////////////////////////
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Comment(String),
+    Angle(String),
+    Comma,
+    Colon,
+    Semicolon,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, std::io::Error> {
+    let unterminated = |what: &str| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("unterminated {} in address list: {:?}", what, s),
+        )
+    };
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            ',' => tokens.push(Token::Comma),
+            ':' => tokens.push(Token::Colon),
+            ';' => tokens.push(Token::Semicolon),
+            '"' => {
+                let mut quoted = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => quoted.extend(chars.next()),
+                        Some('"') => break,
+                        Some(c) => quoted.push(c),
+                        None => return Err(unterminated("quoted string")),
+                    }
+                }
+                tokens.push(Token::Quoted(quoted));
+            }
+            '(' => {
+                let mut comment = String::new();
+                let mut depth = 1;
+                loop {
+                    match chars.next() {
+                        Some('\\') => comment.extend(chars.next()),
+                        Some('(') => {
+                            depth += 1;
+                            comment.push('(');
+                        }
+                        Some(')') => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                            comment.push(')');
+                        }
+                        Some(c) => comment.push(c),
+                        None => return Err(unterminated("comment")),
+                    }
+                }
+                tokens.push(Token::Comment(comment.trim().to_string()));
+            }
+            '<' => {
+                let mut angle = String::new();
+                loop {
+                    match chars.next() {
+                        Some('>') => break,
+                        Some(c) if c.is_whitespace() => {}
+                        Some(c) => angle.push(c),
+                        None => return Err(unterminated("angle address")),
+                    }
+                }
+                // Drop obsolete source routes:  <@relay.example:jane@example.org>.
+                if angle.starts_with('@') {
+                    if let Some((_, addr)) = angle.split_once(':') {
+                        angle = addr.to_string();
+                    }
+                }
+                tokens.push(Token::Angle(angle));
+            }
+            c => {
+                let mut word = String::from(c);
+                while let Some(&d) = chars.peek() {
+                    if d.is_whitespace() || "\",:;()<>".contains(d) {
+                        break;
+                    }
+                    word.push(d);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Collect the tokens of one mailbox or group name, returning them and the token that ended them.
+fn parse_mailbox_tokens(
+    tokens: &mut std::iter::Peekable<std::vec::IntoIter<Token>>,
+) -> (Vec<Token>, Option<Token>) {
+    let mut collected = vec![];
+    for token in tokens.by_ref() {
+        match token {
+            Token::Comma | Token::Colon | Token::Semicolon => return (collected, Some(token)),
+            _ => collected.push(token),
+        }
+    }
+    (collected, None)
+}
+
+fn to_mailbox(tokens: Vec<Token>) -> Option<Mailbox> {
+    if let Some(idx) = tokens.iter().position(|t| matches!(t, Token::Angle(_))) {
+        let Token::Angle(address) = &tokens[idx] else {
+            unreachable!();
+        };
+        let name = phrase_text(&tokens[..idx]);
+        let name = if name.is_empty() {
+            comment_text(&tokens[idx + 1..])
+        } else {
+            Some(name)
+        };
+        return Some(Mailbox {
+            name,
+            address: address.clone(),
+        });
+    }
+    let mut address = String::new();
+    for token in tokens.iter() {
+        match token {
+            Token::Word(word) => address.push_str(word),
+            Token::Quoted(quoted) => address.push_str(&format!("\"{}\"", quoted)),
+            _ => {}
+        }
+    }
+    if address.is_empty() {
+        return None;
+    }
+    Some(Mailbox {
+        name: comment_text(&tokens),
+        address,
+    })
+}
+
+fn phrase_text(tokens: &[Token]) -> String {
+    tokens
+        .iter()
+        .filter_map(|t| match t {
+            Token::Word(word) => Some(word.as_str()),
+            Token::Quoted(quoted) => Some(quoted.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn comment_text(tokens: &[Token]) -> Option<String> {
+    tokens.iter().find_map(|t| match t {
+        Token::Comment(comment) if !comment.is_empty() => Some(comment.clone()),
+        _ => None,
+    })
+}
+
+fn quote_phrase(phrase: &str) -> String {
+    let atext = |c: char| c.is_alphanumeric() || "!#$%&'*+-/=?^_`{|}~ ".contains(c);
+    if !phrase.is_empty() && phrase.chars().all(atext) && phrase.trim() == phrase {
+        phrase.to_string()
+    } else {
+        format!("\"{}\"", phrase.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mailbox(name: Option<&str>, address: &str) -> Mailbox {
+        Mailbox {
+            name: name.map(String::from),
+            address: address.to_string(),
+        }
+    }
+
+    #[test]
+    fn plain_and_named_mailboxes() {
+        let list = "alice@example.org, Bob <bob@example.org>"
+            .parse::<AddressList>()
+            .unwrap();
+        assert_eq!(
+            vec![
+                Address::Mailbox(mailbox(None, "alice@example.org")),
+                Address::Mailbox(mailbox(Some("Bob"), "bob@example.org")),
+            ],
+            list.addresses
+        );
+    }
+
+    #[test]
+    fn quoted_names_keep_their_commas() {
+        let list = r#""Doe, Jane" <jane@example.org>, "Q \"Quote\" R" <q@example.org>"#
+            .parse::<AddressList>()
+            .unwrap();
+        let mailboxes = list.mailboxes().cloned().collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                mailbox(Some("Doe, Jane"), "jane@example.org"),
+                mailbox(Some("Q \"Quote\" R"), "q@example.org"),
+            ],
+            mailboxes
+        );
+        assert_eq!(list, list.to_string().parse::<AddressList>().unwrap());
+    }
+
+    #[test]
+    fn quoted_local_parts_split_at_the_last_at() {
+        let list = r#""odd@local"@example.org"#.parse::<AddressList>().unwrap();
+        let mailbox = list.mailboxes().next().unwrap();
+        assert_eq!(Some("example.org"), mailbox.domain());
+        assert!(mailbox.local_part().contains("odd@local"));
+    }
+
+    #[test]
+    fn groups_hold_their_members() {
+        let list = "Team: a@example.org, B <b@example.org>;, c@example.org, Empty:;"
+            .parse::<AddressList>()
+            .unwrap();
+        assert_eq!(
+            vec![
+                Address::Group(
+                    "Team".to_string(),
+                    vec![
+                        mailbox(None, "a@example.org"),
+                        mailbox(Some("B"), "b@example.org"),
+                    ]
+                ),
+                Address::Mailbox(mailbox(None, "c@example.org")),
+                Address::Group("Empty".to_string(), vec![]),
+            ],
+            list.addresses
+        );
+        assert_eq!(3, list.mailboxes().count());
+        assert!(list.contains("B@EXAMPLE.ORG"));
+        assert_eq!(list, list.to_string().parse::<AddressList>().unwrap());
+    }
+
+    #[test]
+    fn comments_are_ignored() {
+        let list = "alice@example.org (Alice at work)"
+            .parse::<AddressList>()
+            .unwrap();
+        assert_eq!(
+            vec!["alice@example.org"],
+            list.mailboxes()
+                .map(|m| m.address.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn dedup_and_retain_keep_group_names() {
+        let mut list = "a@example.org, G: A@example.org, b@example.org;"
+            .parse::<AddressList>()
+            .unwrap();
+        list.dedup();
+        list.retain(|mailbox| mailbox.address != "b@example.org");
+        assert_eq!("a@example.org, G: ;", list.to_string());
+    }
+
+    #[test]
+    fn non_ascii_names_are_encoded() {
+        let list = "J\u{f6}rg <jorg@example.org>"
+            .parse::<AddressList>()
+            .unwrap();
+        let encoded = list.encoded();
+        assert!(encoded.is_ascii());
+        assert_eq!(
+            list,
+            rfc2047::decode_structured(&encoded)
+                .parse::<AddressList>()
+                .unwrap()
+        );
+    }
+}
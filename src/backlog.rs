@@ -0,0 +1,89 @@
+//! Jobs held back while the model backend is unreachable, so an outage costs one notice rather than
+//! an error reply per prompt.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::Entry;
+
+////////////////////////////////////////////// Backlog /////////////////////////////////////////////
+
+/// The jobs waiting out an outage.  Clones share the same backlog.
+#[derive(Clone, Debug, Default)]
+pub struct Backlog {
+    outage: Arc<Mutex<Outage>>,
+}
+
+#[derive(Debug, Default)]
+struct Outage {
+    down: bool,
+    // Set once the user is told of an outage, and cleared only when a job next succeeds, so a backend
+    // that answers probes but fails every prompt is reported once rather than on every release.
+    noticed: bool,
+    held: Vec<Entry>,
+}
+
+impl Backlog {
+    /// True while the backend is believed to be down.
+    pub fn is_down(&self) -> bool {
+        self.outage().down
+    }
+
+    /// Hold `entry` until the backend is back.  Returns true if the user should be told of the
+    /// outage, i.e. no job has succeeded since the last time this returned true.
+    pub fn hold(&self, entry: Entry) -> bool {
+        let mut outage = self.outage();
+        let notice = !outage.noticed;
+        outage.down = true;
+        outage.noticed = true;
+        outage.held.push(entry);
+        notice
+    }
+
+    /// Note that a job succeeded, so the backend really is back and the next outage is news.
+    pub fn recovered(&self) {
+        self.outage().noticed = false;
+    }
+
+    /// The number of jobs held.
+    pub fn len(&self) -> usize {
+        self.outage().held.len()
+    }
+
+    /// True if no jobs are held.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The Message-ID and maildir name of the prompt of every held job that has a Message-ID.
+    pub fn prompts(&self) -> Vec<(String, String)> {
+        self.outage()
+            .held
+            .iter()
+            .filter_map(|entry| {
+                let msg_id = entry.job.message_id.clone()?;
+                Some((msg_id, entry.job.prompt.clone()))
+            })
+            .collect()
+    }
+
+    /// Take the held jobs whose prompt has Message-ID `msg_id` out of the backlog.
+    pub fn withdraw(&self, msg_id: &str) -> Vec<Entry> {
+        let mut outage = self.outage();
+        let (withdrawn, held) = std::mem::take(&mut outage.held)
+            .into_iter()
+            .partition(|entry| entry.job.message_id.as_deref() == Some(msg_id));
+        outage.held = held;
+        withdrawn
+    }
+
+    /// Note that the backend answers again and hand over the jobs held in the meantime, oldest first.
+    pub fn release(&self) -> Vec<Entry> {
+        let mut outage = self.outage();
+        outage.down = false;
+        std::mem::take(&mut outage.held)
+    }
+
+    fn outage(&self) -> MutexGuard<'_, Outage> {
+        self.outage.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
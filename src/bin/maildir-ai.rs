@@ -1,7 +1,10 @@
 use arrrg::CommandLine;
 use utf8path::Path;
 
-use maildir_ai::{init, maintain, MaintainOptions};
+use maildir_ai::{
+    extract_recipients, init, maintain, prompt_messages, queue, requeue, Config, Header,
+    MaintainOptions, Message,
+};
 
 #[derive(Clone, Debug, Default, Eq, PartialEq, arrrg_derive::CommandLine)]
 struct Options {}
@@ -14,6 +17,9 @@ commands:
 init        initialize a new maildir-ai database
 run         invoke mutt configured to access the current maildir-ai database
 maintain    maintain the maildir-ai database
+clean-prompt show the conversation a model will receive for a message
+requeue     send a fixed message back from Quarantine to be answered
+queue       list the jobs waiting for, or getting, an answer
 "
     );
 }
@@ -88,11 +94,126 @@ async fn main() {
                     eprintln!("not a file: {}", path);
                     continue;
                 }
-                let content = std::fs::read_to_string(&path).unwrap();
+                let content = std::fs::read(&path).unwrap();
                 let formatted = maildir_ai::format_reply("cl4p-tp@rave", &content).unwrap();
                 println!("{}\n", formatted);
             }
         }
+        "clean-prompt" => {
+            if args.len() != 2 && args.len() != 3 {
+                eprintln!("expected one or two arguments for the clean-prompt command");
+                eprintln!("USAGE: maildir-ai clean-prompt <file> [<model-address>]");
+                std::process::exit(1);
+            }
+            let path = Path::new(&args[1]);
+            let content = match std::fs::read(&path) {
+                Ok(content) => content,
+                Err(err) => {
+                    eprintln!("could not read {}: {}", path, err);
+                    std::process::exit(1);
+                }
+            };
+            // A message is answered with the configuration and the threads of its knowledge base.
+            let knowledge_base = knowledge_base_of(&args[1]);
+            let config = match Config::load(&knowledge_base) {
+                Ok(config) => config,
+                Err(err) => {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                }
+            };
+            // Without an address, show what the first model the message is addressed to receives.
+            let to = args.get(2).cloned().or_else(|| {
+                extract_recipients(&content)
+                    .mailboxes()
+                    .map(|mailbox| mailbox.address.clone())
+                    .find(|address| config.panel(address).is_none() && config.is_model(address))
+            });
+            let Some(to) = to else {
+                eprintln!("{} is addressed to no model; name one", path);
+                std::process::exit(1);
+            };
+            let messages = match prompt_messages(&config, &knowledge_base, &to, &content) {
+                Ok(messages) => messages,
+                Err(err) => {
+                    eprintln!("could not rebuild the thread of {}: {}", path, err);
+                    std::process::exit(1);
+                }
+            };
+            let persona = config.persona(&to);
+            println!("model: {}", persona.model());
+            if persona.template.is_some() {
+                println!("template: the conversation is sent through the persona's template");
+            }
+            if let Some(system) = &persona.system {
+                println!("\n--- system ---\n{}", system.trim_end());
+            }
+            for message in messages {
+                let images = message.images.as_ref().map(Vec::len).unwrap_or(0);
+                match images {
+                    0 => println!("\n--- {} ---", message.role),
+                    1 => println!("\n--- {} (1 image) ---", message.role),
+                    n => println!("\n--- {} ({} images) ---", message.role, n),
+                }
+                println!("{}", message.content.trim_end());
+            }
+        }
+        "requeue" => {
+            if args.len() != 2 {
+                eprintln!("expected exactly one argument for the requeue command");
+                eprintln!("USAGE: maildir-ai requeue <file>");
+                std::process::exit(1);
+            }
+            let path = Path::new(&args[1]);
+            match requeue(&knowledge_base_of(&args[1]), &path) {
+                Ok(sent) => eprintln!("requeued: {}", sent),
+                Err(err) => {
+                    eprintln!("could not requeue {}: {}", path, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        "queue" => {
+            if args.len() != 2 {
+                eprintln!("expected exactly one argument for the queue command");
+                eprintln!("USAGE: maildir-ai queue <knowledge-base>");
+                std::process::exit(1);
+            }
+            let knowledge_base = Path::new(&args[1]);
+            let entries = match queue(&knowledge_base) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    eprintln!("could not read the journal: {}", err);
+                    std::process::exit(1);
+                }
+            };
+            if entries.is_empty() {
+                println!("no jobs");
+            }
+            for entry in entries {
+                let subject = entry
+                    .job
+                    .find_prompt(&knowledge_base)
+                    .and_then(|path| std::fs::read(path).ok())
+                    .and_then(|email| {
+                        Message::parse(email)
+                            .headers
+                            .into_iter()
+                            .find_map(|header| match header {
+                                Header::Subject(subject) => Some(subject),
+                                _ => None,
+                            })
+                    })
+                    .unwrap_or_default();
+                println!(
+                    "{:<8} p{}  {}  {:?}",
+                    entry.state, entry.job.priority, entry.job.task, subject
+                );
+                if let Some(error) = entry.error {
+                    println!("         {}", error);
+                }
+            }
+        }
         _ => {
             eprintln!("unknown command: {}\n", args[0]);
             help();
@@ -100,3 +221,14 @@ async fn main() {
         }
     }
 }
+
+// The knowledge base holding the message at <knowledge-base>/<folder>/cur/<file>.
+fn knowledge_base_of(file: &str) -> Path<'_> {
+    let knowledge_base = std::path::Path::new(file)
+        .ancestors()
+        .nth(3)
+        .and_then(|p| p.to_str())
+        .filter(|p| !p.is_empty())
+        .unwrap_or(".");
+    Path::new(knowledge_base)
+}
@@ -0,0 +1,295 @@
+//! Bounces:  the message delivered in place of a reply when a model cannot answer.
+//!
+//! A bounce is a delivery status notification in the manner of RFC 3464, so mail clients and
+//! scripts can read it, and it threads under the message it answers.  It is built from whatever of
+//! the original's headers can be salvaged, so even a message with broken headers gets one.
+
+use super::mime::parse_headers;
+use super::{
+    generate_message_id, hops, is_transient, maildir, references, thread, AddressList, Header,
+    ParameterizedValue, LOOP_TOKEN, X_AI_HOPS, X_LOOP,
+};
+
+/// The address bounces come from.
+pub const MAILER_DAEMON: &str = "Mail Delivery System <MAILER-DAEMON@localhost>";
+
+/// The longest the diagnostic in a bounce may be, keeping its line within the limits of RFC 5322.
+const MAX_DIAGNOSTIC: usize = 900;
+
+////////////////////////////////////////////// Failure /////////////////////////////////////////////
+
+/// Why a model did not answer a message.
+#[derive(Debug)]
+pub struct Failure<'a> {
+    /// The address the message was sent to.
+    pub recipient: &'a str,
+    /// The model behind the address.
+    pub model: &'a str,
+    /// The URL of the backend serving the model.
+    pub backend: &'a str,
+    /// How many times the model was asked; zero if the message failed before it got that far.
+    pub attempts: u32,
+    /// What went wrong.
+    pub error: &'a std::io::Error,
+}
+
+impl Failure<'_> {
+    /// "transient" if the failure may pass on its own, otherwise "permanent".
+    pub fn kind(&self) -> &'static str {
+        if is_transient(self.error) {
+            "transient"
+        } else {
+            "permanent"
+        }
+    }
+}
+
+/////////////////////////////////////////////// bounce /////////////////////////////////////////////
+
+/// Format a bounce for `message`, explaining `failure`.  This cannot fail.
+///
+/// The bounce has three parts:  a note for people, a message/delivery-status part with the kind of
+/// failure, the model, the backend, and the number of attempts, and the original message attached
+/// as message/rfc822.
+pub fn format_bounce(failure: &Failure<'_>, message: impl AsRef<[u8]>) -> String {
+    let message = message.as_ref();
+    let orig_headers = parse_headers(message);
+    let subject = orig_headers
+        .iter()
+        .find_map(|header| match header {
+            Header::Subject(x) => Some(sanitize(x)),
+            _ => None,
+        })
+        .unwrap_or_default();
+    let mut headers = vec![
+        Header::From(MAILER_DAEMON.to_string()),
+        Header::Subject(format!("Undeliverable: {}", subject).trim_end().to_string()),
+        Header::Date(chrono::Utc::now().to_rfc2822()),
+        Header::MessageID(generate_message_id()),
+    ];
+    // The bounce goes where the sender asked replies to go, or else back to the sender, if either
+    // can be made out.
+    let address = |name: &str| {
+        orig_headers
+            .iter()
+            .filter(|header| header.is(name))
+            .find_map(|header| header.value().parse::<AddressList>().ok())
+            .filter(|to| !to.is_empty())
+    };
+    if let Some(to) = address("Reply-To").or_else(|| address("From")) {
+        headers.push(Header::To(to.to_string()));
+    }
+    let orig_msg_id = orig_headers.iter().find_map(|header| match header {
+        Header::MessageID(x) => thread::split_msg_ids(x).into_iter().next(),
+        _ => None,
+    });
+    let references = references(&orig_headers, orig_msg_id.as_deref());
+    if let Some(orig_msg_id) = &orig_msg_id {
+        headers.push(Header::InReplyTo(orig_msg_id.clone()));
+    }
+    if !references.is_empty() {
+        headers.push(Header::References(references.join(" ")));
+    }
+    headers.push(Header::Other(X_LOOP.to_string(), LOOP_TOKEN.to_string()));
+    headers.push(Header::Other(
+        X_AI_HOPS.to_string(),
+        (hops(&orig_headers) + 1).to_string(),
+    ));
+    // Boundaries may only use a few characters, so keep those of a fresh message ID.
+    let boundary = format!(
+        "=_bounce.{}",
+        generate_message_id()
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '.')
+            .collect::<String>()
+    );
+    headers.push(Header::MimeVersion("1.0".to_string()));
+    headers.push(Header::ContentType(
+        ParameterizedValue::new("multipart/report")
+            .with_param("report-type", "delivery-status")
+            .with_param("boundary", boundary.clone()),
+    ));
+    let diagnostic = diagnostic(&failure.error.to_string());
+    let (action, status) = if is_transient(failure.error) {
+        ("delayed", "4.0.0")
+    } else {
+        ("failed", "5.0.0")
+    };
+    let asked = match failure.attempts {
+        0 => "The model was not asked".to_string(),
+        1 => format!("The model was asked once at {}", failure.backend),
+        n => format!("The model was asked {} times at {}", n, failure.backend),
+    };
+    let note = format!(
+        "{} could not answer your message.\n\n    {}\n\n{}.  Your message is attached.\n",
+        failure.recipient, diagnostic, asked,
+    );
+    let status = format!(
+        "Reporting-MTA: x-maildir-ai; {}\n\n\
+         Final-Recipient: rfc822; {}\n\
+         Action: {}\n\
+         Status: {}\n\
+         Diagnostic-Code: x-maildir-ai; {}\n\
+         X-AI-Error-Kind: {}\n\
+         X-AI-Model: {}\n\
+         X-AI-Backend: {}\n\
+         X-AI-Attempts: {}\n",
+        maildir::hostname(),
+        sanitize(failure.recipient),
+        action,
+        status,
+        diagnostic,
+        failure.kind(),
+        sanitize(failure.model),
+        sanitize(failure.backend),
+        failure.attempts,
+    );
+    let mut original = String::from_utf8_lossy(message).into_owned();
+    if !original.ends_with('\n') {
+        original.push('\n');
+    }
+    format!(
+        "{}\n\n\
+         This is a MIME-encapsulated delivery status notification.\n\n\
+         --{boundary}\n\
+         Content-Type: text/plain; charset=utf-8\n\
+         Content-Transfer-Encoding: 8bit\n\
+         Content-Description: Notification\n\n\
+         {note}\n\
+         --{boundary}\n\
+         Content-Type: message/delivery-status\n\
+         Content-Description: Delivery report\n\n\
+         {status}\n\
+         --{boundary}\n\
+         Content-Type: message/rfc822\n\
+         Content-Transfer-Encoding: 8bit\n\
+         Content-Description: Undelivered message\n\n\
+         {original}\n\
+         --{boundary}--\n",
+        Header::to_block(&headers),
+    )
+}
+
+// One line of printable text, short enough for a header.
+fn diagnostic(error: &str) -> String {
+    let mut diagnostic = sanitize(error);
+    if diagnostic.len() > MAX_DIAGNOSTIC {
+        let mut end = MAX_DIAGNOSTIC;
+        while !diagnostic.is_char_boundary(end) {
+            end -= 1;
+        }
+        diagnostic.truncate(end);
+        diagnostic.push_str("...");
+    }
+    diagnostic
+}
+
+// Collapse runs of whitespace and control characters so that text from a broken message or an error
+// cannot break the header or line it goes into.
+fn sanitize(s: &str) -> String {
+    s.split(|c: char| c.is_whitespace() || c.is_control())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mime::Part;
+
+    fn failure(error: &std::io::Error) -> Failure<'_> {
+        Failure {
+            recipient: "llama3@rave",
+            model: "llama3",
+            backend: "http://localhost:11434",
+            attempts: 4,
+            error,
+        }
+    }
+
+    fn to_of(bounce: &str) -> Option<String> {
+        parse_headers(bounce.as_bytes())
+            .into_iter()
+            .find_map(|header| match header {
+                Header::To(x) => Some(x),
+                _ => None,
+            })
+    }
+
+    #[test]
+    fn bounces_go_to_reply_to_before_from() {
+        let error = std::io::Error::other("model not found");
+        let message = "From: Alice <alice@example.org>\n\
+                       Reply-To: list@example.org\n\
+                       Message-ID: <one@example.org>\n\
+                       \n\
+                       Hello.\n";
+        let bounce = format_bounce(&failure(&error), message);
+        assert_eq!(Some("list@example.org".to_string()), to_of(&bounce));
+        let message = "From: Alice <alice@example.org>\n\nHello.\n";
+        let bounce = format_bounce(&failure(&error), message);
+        assert_eq!(
+            Some("Alice <alice@example.org>".to_string()),
+            to_of(&bounce)
+        );
+    }
+
+    #[test]
+    fn bounces_are_delivery_reports_with_the_original_attached() {
+        let error = std::io::Error::other("model not found");
+        let message = "From: Alice <alice@example.org>\n\
+                       Subject: hello\n\
+                       Message-ID: <one@example.org>\n\
+                       References: <zero@example.org>\n\
+                       \n\
+                       Hello.\n";
+        let bounce = format_bounce(&failure(&error), message);
+        let report = Part::parse(&bounce);
+        let content_type = report.content_type();
+        assert!(content_type.is("multipart/report"));
+        assert_eq!(Some("delivery-status"), content_type.param("report-type"));
+        let headers = &report.headers;
+        assert!(headers.contains(&Header::Subject("Undeliverable: hello".to_string())));
+        assert!(headers.contains(&Header::InReplyTo("<one@example.org>".to_string())));
+        assert!(headers.contains(&Header::References(
+            "<zero@example.org> <one@example.org>".to_string()
+        )));
+        let types = report
+            .parts
+            .iter()
+            .map(|part| part.content_type().value.to_ascii_lowercase())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec!["text/plain", "message/delivery-status", "message/rfc822"],
+            types
+        );
+        let status = String::from_utf8_lossy(&report.parts[1].body).into_owned();
+        assert!(status.contains("Final-Recipient: rfc822; llama3@rave\n"));
+        assert!(status.contains("Action: failed\nStatus: 5.0.0\n"));
+        assert!(status.contains("X-AI-Attempts: 4\n"));
+        assert_eq!(message.as_bytes(), &report.parts[2].body[..]);
+    }
+
+    #[test]
+    fn broken_headers_still_get_a_report() {
+        let error = std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out\r\nInjected: yes");
+        let message = "this is not a header\n\
+                       From: <<<nobody>>>\n\
+                       Subject: two\r\n\x07lines\n\
+                       Content-Type: multipart/mixed\n\
+                       \n\
+                       Hello.\n";
+        let bounce = format_bounce(&failure(&error), message);
+        let report = Part::parse(&bounce);
+        assert!(report.content_type().is("multipart/report"));
+        assert_eq!(3, report.parts.len());
+        assert!(report.parts[2].content_type().is("message/rfc822"));
+        let status = String::from_utf8_lossy(&report.parts[1].body).into_owned();
+        assert!(status.contains("Action: delayed\nStatus: 4.0.0\n"));
+        assert!(status.contains("Diagnostic-Code: x-maildir-ai; timed out Injected: yes\n"));
+        assert!(!parse_headers(bounce.as_bytes())
+            .iter()
+            .any(|header| header.is("Injected")));
+    }
+}
@@ -0,0 +1,112 @@
+//! Strip quoted text, attribution lines, and signatures from a prompt.
+
+//////////////////////////////////////////// clean_prompt //////////////////////////////////////////
+
+/// Clean the body of a message for presentation to a model.
+///
+/// Quoted lines (those starting with `>`), the "On ... wrote:" attribution that introduces them,
+/// and everything after a `-- ` signature separator are removed.  Unquoted text interleaved with
+/// quotes is kept so that inline replies survive.
+pub fn clean_prompt(body: impl AsRef<str>) -> String {
+    let mut lines = body.as_ref().lines().collect::<Vec<_>>();
+    if let Some(sig) = lines.iter().position(|line| *line == "-- ") {
+        lines.truncate(sig);
+    }
+    let quoted = |line: &str| line.trim_start().starts_with('>');
+    let mut keep = vec![true; lines.len()];
+    for (idx, line) in lines.iter().enumerate() {
+        if quoted(line) {
+            keep[idx] = false;
+            continue;
+        }
+        if !line.trim_end().ends_with("wrote:") {
+            continue;
+        }
+        let Some(next) = lines[idx + 1..].iter().find(|x| !x.trim().is_empty()) else {
+            continue;
+        };
+        if !quoted(next) {
+            continue;
+        }
+        keep[idx] = false;
+        // Attributions get wrapped by some clients:  "On <date>, <who>\nwrote:".
+        if idx > 0 && !line.starts_with("On ") && lines[idx - 1].starts_with("On ") {
+            keep[idx - 1] = false;
+        }
+    }
+    let mut cleaned = String::new();
+    let mut blank = false;
+    for (line, keep) in lines.iter().zip(keep.iter()) {
+        if !keep {
+            continue;
+        }
+        if line.trim().is_empty() {
+            blank = true;
+            continue;
+        }
+        if blank && !cleaned.is_empty() {
+            cleaned.push('\n');
+        }
+        blank = false;
+        cleaned.push_str(line.trim_end());
+        cleaned.push('\n');
+    }
+    cleaned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_quotes_and_attribution() {
+        let body = "On Mon, 1 Jan 2024 00:00:00 +0000, Bot <bot@rave> wrote:\n\
+                    > First answer.\n\
+                    >\n\
+                    > More of it.\n\
+                    \n\
+                    Second question.\n";
+        assert_eq!("Second question.\n", clean_prompt(body));
+    }
+
+    #[test]
+    fn keeps_inline_replies() {
+        let body = "On Mon, 1 Jan 2024, Bot <bot@rave> wrote:\n\
+                    > Point one.\n\
+                    \n\
+                    I agree.\n\
+                    \n\
+                    > Point two.\n\
+                    \n\
+                    I don't.\n";
+        assert_eq!("I agree.\n\nI don't.\n", clean_prompt(body));
+    }
+
+    #[test]
+    fn drops_wrapped_attribution() {
+        let body = "Thanks.\n\
+                    \n\
+                    On Mon, 1 Jan 2024 00:00:00 +0000, A Very Long Name\n\
+                    <someone@example.org> wrote:\n\
+                    > Quoted.\n";
+        assert_eq!("Thanks.\n", clean_prompt(body));
+    }
+
+    #[test]
+    fn keeps_wrote_that_introduces_no_quote() {
+        let body = "Here is what I wrote:\nthe draft itself\n";
+        assert_eq!(body, clean_prompt(body));
+    }
+
+    #[test]
+    fn drops_signature() {
+        let body = "Question?\n\n-- \nTester\nhttps://example.org\n";
+        assert_eq!("Question?\n", clean_prompt(body));
+    }
+
+    #[test]
+    fn collapses_blank_runs() {
+        let body = "\n\nOne.\n\n\n\nTwo.   \n\n";
+        assert_eq!("One.\n\nTwo.\n", clean_prompt(body));
+    }
+}
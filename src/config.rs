@@ -0,0 +1,252 @@
+//! The per-knowledge-base configuration stored in `.maildir-ai.toml`.
+
+use std::collections::BTreeMap;
+
+use utf8path::Path;
+
+use super::{Mailbox, CONFIG};
+
+/////////////////////////////////////////// DEFAULT_CONFIG /////////////////////////////////////////
+
+/// The configuration written by `init`.
+pub const DEFAULT_CONFIG: &str = r#"# maildir-ai configuration
+#
+# Mail to an address listed under [personas] is answered by the configured model.  Mail to any other
+# model address is answered by the model named by the local part of the address, so llama3@rave is
+# answered by llama3.
+#
+# Only these domains and addresses, plus the personas, are models.  Everyone else on a message is a
+# person and is left alone.  With neither set, every recipient is treated as a model.
+model_domains = ["rave"]
+# model_addresses = ["llama3@example.org"]
+#
+# Mail to a panel is answered by each member individually and then by one email comparing the
+# answers.
+# [panels."panel@rave"]
+# members = ["llama3@rave", "mistral@rave", "qwen2@rave"]
+#
+# Mail to two or more models with an X-AI-Debate: N header has the models answer one another for N
+# rounds, up to this many.
+# max_debate_rounds = 5
+#
+# Mail that is more than this many replies away from the prompt that started it is quarantined rather
+# than answered.
+# max_hops = 8
+#
+# At most this many requests go to the backend at once, and at most max_requests_per_model of them
+# to the same model.  The rest wait their turn, most urgent X-Priority first and then oldest first.
+# max_requests = 4
+# max_requests_per_model = 1
+#
+# Requests that fail for a reason that may pass, such as ollama being restarted, are retried this many
+# times in all, waiting backoff_secs and then twice as long each time up to max_backoff_secs.  Set
+# retry under a persona to override it for that model.
+# retry = { attempts = 4, backoff_secs = 2, max_backoff_secs = 60 }
+#
+# Prompts that run out of retries are held until the backend answers again.  A prompt held this many
+# times is bounced rather than held again.
+# max_holds = 10
+#
+# Text attachments (source, logs, CSV) are included in the prompt up to this many bytes in all.  The
+# newest message's attachments come first, so those earlier in the thread are the first to be cut.
+# context_budget = 32768
+#
+# [personas."reviewer@rave"]
+# model = "llama3.1"
+# system = "You are a meticulous code reviewer.  Be terse."
+# options = { temperature = 0.2, num_ctx = 8192 }
+#
+# Setting a template sends the thread through ollama's generate endpoint with the template applied.
+# template = "{{ .System }}\n\n{{ .Prompt }}"
+"#;
+
+/////////////////////////////////////////////// Config /////////////////////////////////////////////
+
+/// The configuration of a knowledge base.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Config {
+    /// The number of bytes of text attachments to include in the prompt of each message.
+    pub context_budget: usize,
+    /// Every address in these domains is a model.
+    pub model_domains: Vec<String>,
+    /// Addresses that are models regardless of their domain.
+    pub model_addresses: Vec<String>,
+    /// Personas keyed by email address.
+    pub personas: BTreeMap<String, Persona>,
+    /// Panels keyed by email address.
+    pub panels: BTreeMap<String, Panel>,
+    /// The most rounds a debate may run, whatever X-AI-Debate asks for.
+    pub max_debate_rounds: usize,
+    /// The most replies a message may be from its prompt and still be answered.
+    pub max_hops: usize,
+    /// How to retry requests that fail for a reason that may pass.
+    pub retry: Retry,
+    /// The most times a job may be held for want of a backend before it is bounced.
+    pub max_holds: u32,
+    /// The most requests to have in flight to the backend at once.
+    pub max_requests: usize,
+    /// The most requests to have in flight to any one model at once.
+    pub max_requests_per_model: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            context_budget: 32768,
+            model_domains: vec![],
+            model_addresses: vec![],
+            personas: BTreeMap::new(),
+            panels: BTreeMap::new(),
+            max_debate_rounds: 5,
+            max_hops: 8,
+            retry: Retry::default(),
+            max_holds: 10,
+            max_requests: 4,
+            max_requests_per_model: 1,
+        }
+    }
+}
+
+impl Config {
+    /// Load the configuration of the knowledge base, or the default if there is none.
+    pub fn load(knowledge_base: &Path<'_>) -> Result<Self, std::io::Error> {
+        let path = knowledge_base.join(CONFIG);
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default());
+            }
+            Err(err) => {
+                return Err(err);
+            }
+        };
+        toml::from_str(&contents).map_err(|err| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("could not parse {}: {}", path, err),
+            )
+        })
+    }
+
+    /// True if mail to `address` should be answered by a model.
+    ///
+    /// Personas and panels are always models.  If neither `model_domains` nor `model_addresses` is
+    /// set, every address is a model.
+    pub fn is_model(&self, address: &str) -> bool {
+        let mailbox = Mailbox::new(address.trim());
+        if self.panels.keys().any(|k| mailbox.is(k)) || self.personas.keys().any(|k| mailbox.is(k))
+        {
+            return true;
+        }
+        if self.model_domains.is_empty() && self.model_addresses.is_empty() {
+            return true;
+        }
+        let domain = mailbox.domain().unwrap_or_default();
+        self.model_addresses.iter().any(|a| mailbox.is(a))
+            || self
+                .model_domains
+                .iter()
+                .any(|d| d.trim_start_matches('@').eq_ignore_ascii_case(domain))
+    }
+
+    /// The panel at `address`, if there is one.
+    pub fn panel(&self, address: &str) -> Option<&Panel> {
+        let address = address.trim();
+        self.panels
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(address))
+            .map(|(_, v)| v)
+    }
+
+    /// The persona that answers mail sent to `address`.
+    ///
+    /// Addresses without a persona get one whose model is the local part of the address.
+    pub fn persona(&self, address: &str) -> Persona {
+        let mailbox = Mailbox::new(address.trim());
+        let mut persona = self
+            .personas
+            .iter()
+            .find(|(k, _)| mailbox.is(k))
+            .map(|(_, v)| v.clone())
+            .unwrap_or_default();
+        if persona.model.is_none() {
+            persona.model = Some(mailbox.local_part().to_string());
+        }
+        persona
+    }
+
+    /// The retry policy for mail sent to `address`.
+    pub fn retry(&self, address: &str) -> Retry {
+        self.persona(address).retry.unwrap_or(self.retry)
+    }
+}
+
+////////////////////////////////////////////// Persona /////////////////////////////////////////////
+
+/// How to answer mail sent to one address.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Persona {
+    /// The ollama model to use.
+    pub model: Option<String>,
+    /// The system prompt to give the model.
+    pub system: Option<String>,
+    /// The prompt template to use.  Requests with a template go through the generate endpoint.
+    pub template: Option<String>,
+    /// Model options, e.g. temperature or num_ctx, passed through to ollama verbatim.
+    pub options: Option<serde_json::Map<String, serde_json::Value>>,
+    /// How to retry this model, if not the knowledge base's default.
+    pub retry: Option<Retry>,
+}
+
+impl Persona {
+    /// The model this persona uses.
+    pub fn model(&self) -> &str {
+        self.model.as_deref().unwrap_or_default()
+    }
+}
+
+/////////////////////////////////////////////// Panel //////////////////////////////////////////////
+
+/// A group of models that each answer mail sent to the panel's address.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Panel {
+    /// The addresses of the models on the panel.
+    pub members: Vec<String>,
+}
+
+/////////////////////////////////////////////// Retry //////////////////////////////////////////////
+
+/// How to retry a request that fails for a reason that may pass.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Retry {
+    /// The most times to make the request, counting the first.
+    pub attempts: u32,
+    /// Seconds to wait before the first retry.  Each retry after waits twice as long as the last.
+    pub backoff_secs: u64,
+    /// The longest to wait between two attempts, in seconds.
+    pub max_backoff_secs: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: 4,
+            backoff_secs: 2,
+            max_backoff_secs: 60,
+        }
+    }
+}
+
+impl Retry {
+    /// How long to wait after the `attempt`'th attempt fails, counting from one.
+    pub fn backoff(&self, attempt: u32) -> std::time::Duration {
+        let secs = self
+            .backoff_secs
+            .saturating_mul(1u64 << attempt.saturating_sub(1).min(32));
+        std::time::Duration::from_secs(secs.min(self.max_backoff_secs))
+    }
+}
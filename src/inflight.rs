@@ -0,0 +1,106 @@
+//! The jobs in flight, known by the Message-ID of their prompt, so that withdrawing a prompt stops
+//! every job answering it.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::sync::watch;
+
+////////////////////////////////////////////// InFlight ////////////////////////////////////////////
+
+/// The prompts that have jobs in flight.  Clones share the same prompts.
+#[derive(Clone, Debug, Default)]
+pub struct InFlight {
+    prompts: Arc<Mutex<HashMap<String, Prompt>>>,
+}
+
+#[derive(Debug)]
+struct Prompt {
+    name: String,
+    withdrawn: watch::Sender<bool>,
+}
+
+impl InFlight {
+    /// Note a job in flight for the prompt with Message-ID `msg_id` and maildir name `name`.  The job
+    /// stays in flight until the registration is dropped.
+    pub fn register(&self, msg_id: &str, name: &str) -> Registration {
+        let withdrawn = self
+            .lock()
+            .entry(msg_id.to_string())
+            .or_insert_with(|| Prompt {
+                name: name.to_string(),
+                withdrawn: watch::channel(false).0,
+            })
+            .withdrawn
+            .subscribe();
+        Registration {
+            in_flight: self.clone(),
+            msg_id: msg_id.to_string(),
+            withdrawn,
+        }
+    }
+
+    /// Stop every job answering the prompt with Message-ID `msg_id`.  Returns true if there were any.
+    pub fn withdraw(&self, msg_id: &str) -> bool {
+        match self.lock().remove(msg_id) {
+            Some(prompt) => {
+                let _ = prompt.withdrawn.send(true);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// True if no prompt has jobs in flight.
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// The Message-ID and maildir name of every prompt in flight.
+    pub fn prompts(&self) -> Vec<(String, String)> {
+        self.lock()
+            .iter()
+            .map(|(msg_id, prompt)| (msg_id.clone(), prompt.name.clone()))
+            .collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Prompt>> {
+        self.prompts.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+//////////////////////////////////////////// Registration //////////////////////////////////////////
+
+/// One job's place in flight.
+#[derive(Debug)]
+pub struct Registration {
+    in_flight: InFlight,
+    msg_id: String,
+    withdrawn: watch::Receiver<bool>,
+}
+
+impl Registration {
+    /// Resolve once the job's prompt is withdrawn.
+    pub async fn withdrawn(&mut self) {
+        if self
+            .withdrawn
+            .wait_for(|withdrawn| *withdrawn)
+            .await
+            .is_err()
+        {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut prompts = self.in_flight.lock();
+        // The last job to leave takes the prompt with it; this registration is still subscribed.
+        if let Some(prompt) = prompts.get(&self.msg_id) {
+            if prompt.withdrawn.receiver_count() <= 1 {
+                prompts.remove(&self.msg_id);
+            }
+        }
+    }
+}
@@ -0,0 +1,526 @@
+//! The work of answering a prompt, broken into jobs that can stop part way and pick up again.
+
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use utf8path::Path;
+
+use super::mime::parse_headers;
+use super::{
+    format_bounce, format_comparison, is_transient, maildir, priority, reply, save_to_inbox,
+    thread, Config, Failure, Header, MaintainOptions, Scheduler, INBOX, NORMAL_PRIORITY,
+};
+
+//////////////////////////////////////////////// Job ///////////////////////////////////////////////
+
+/// One unit of work for a prompt that has been moved to INBOX.
+#[derive(Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Job {
+    /// The maildir name of the prompt in INBOX, without its info suffix.
+    pub prompt: String,
+    /// What to do with the prompt, and how far along it is.
+    pub task: Task,
+    /// How urgent the job is, from 1 (highest) to 5 (lowest) as in X-Priority.
+    #[serde(default = "normal_priority")]
+    pub priority: u8,
+    /// The Message-ID of the prompt, by which the job is cancelled if the prompt is withdrawn.
+    #[serde(default)]
+    pub message_id: Option<String>,
+}
+
+fn normal_priority() -> u8 {
+    NORMAL_PRIORITY
+}
+
+/// What a job does with its prompt.
+#[derive(Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Task {
+    /// Reply as one model.
+    Reply { to: String },
+    /// Reply as each member of a panel, then compare their answers.  `answers` holds the members
+    /// that have already replied.
+    Panel {
+        panel: String,
+        members: Vec<String>,
+        answers: Vec<(String, String)>,
+    },
+    /// Have the models answer one another, `rounds` times each.  `turn` counts the turns taken and
+    /// `last` names the most recent turn in INBOX.
+    Debate {
+        models: Vec<String>,
+        rounds: usize,
+        turn: usize,
+        last: Option<String>,
+    },
+}
+
+impl std::fmt::Display for Task {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Task::Reply { to } => write!(f, "reply as {}", to),
+            Task::Panel {
+                panel,
+                members,
+                answers,
+            } => write!(
+                f,
+                "panel {} ({} of {} answered)",
+                panel,
+                answers.len(),
+                members.len()
+            ),
+            Task::Debate {
+                models,
+                rounds,
+                turn,
+                ..
+            } => write!(
+                f,
+                "debate between {} (turn {} of {})",
+                models.join(", "),
+                turn,
+                rounds * models.len()
+            ),
+        }
+    }
+}
+
+/// How a run of a job ended.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    /// The job finished.
+    Done,
+    /// The job gave up for the given reason.
+    Failed(String),
+    /// The backend could not be reached for the given reason; this is how far the job got.
+    Held(Job, String),
+    /// The job was cancelled; this is how far it got.
+    Cancelled(Job),
+}
+
+impl Job {
+    /// Create a job for `email`, the prompt at `path`.
+    pub fn new(path: &Path<'_>, email: &[u8], task: Task) -> Self {
+        let message_id = parse_headers(email).iter().find_map(|header| match header {
+            Header::MessageID(x) => thread::split_msg_ids(x).into_iter().next(),
+            _ => None,
+        });
+        Self {
+            prompt: maildir::unique_part(path.basename().as_str()).to_string(),
+            task,
+            priority: priority(email),
+            message_id,
+        }
+    }
+
+    /// Where the prompt is, if it is still in INBOX.
+    pub fn find_prompt(&self, knowledge_base: &Path<'_>) -> Option<Path<'static>> {
+        maildir::find(&knowledge_base.join(INBOX), &self.prompt)
+    }
+
+    /// Give up on the job after it was held `holds` times, delivering a bounce that explains `error`
+    /// for the recipient it still owes an answer.
+    pub fn give_up(
+        &self,
+        options: &MaintainOptions,
+        config: &Config,
+        knowledge_base: &Path<'_>,
+        holds: u32,
+        error: &std::io::Error,
+    ) -> Result<(), String> {
+        let Some(path) = self.find_prompt(knowledge_base) else {
+            return Err(format!("prompt {} is no longer in {}", self.prompt, INBOX));
+        };
+        let email =
+            std::fs::read(&path).map_err(|err| format!("could not read {}: {}", path, err))?;
+        let (recipient, model) = match &self.task {
+            Task::Reply { to } => (to.clone(), config.persona(to).model().to_string()),
+            Task::Panel {
+                panel,
+                members,
+                answers,
+            } => {
+                let unanswered = members
+                    .iter()
+                    .filter(|member| !answers.iter().any(|(answered, _)| answered == *member))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                (panel.clone(), unanswered.join(", "))
+            }
+            Task::Debate { models, turn, .. } => {
+                let to = models[turn % models.len().max(1)].clone();
+                let model = config.persona(&to).model().to_string();
+                (to, model)
+            }
+        };
+        let failure = Failure {
+            recipient: &recipient,
+            model: &model,
+            backend: &options.yammer.url(),
+            attempts: holds.saturating_mul(config.retry(&recipient).attempts),
+            error,
+        };
+        match save_to_inbox(knowledge_base, format_bounce(&failure, &email)) {
+            Some(_) => Ok(()),
+            None => Err(format!("could not deliver the bounce for {}", recipient)),
+        }
+    }
+
+    /// Run the job to completion, or until `cancelled` turns true.  Each request waits its turn with
+    /// `scheduler`.  `progress` is called with the job each time it gets further along, starting
+    /// with its first turn, so that it can be resumed from there.
+    pub async fn run(
+        self,
+        options: &MaintainOptions,
+        config: &Config,
+        knowledge_base: &Path<'_>,
+        scheduler: &Scheduler,
+        mut cancelled: watch::Receiver<bool>,
+        progress: &mut (dyn FnMut(&Job) + Send),
+    ) -> Outcome {
+        let Some(path) = self.find_prompt(knowledge_base) else {
+            return Outcome::Failed(format!("prompt {} is no longer in {}", self.prompt, INBOX));
+        };
+        let email = match std::fs::read(&path) {
+            Ok(email) => email,
+            Err(err) => {
+                return Outcome::Failed(format!("could not read {}: {}", path, err));
+            }
+        };
+        let Job {
+            prompt,
+            task,
+            priority,
+            message_id,
+        } = self;
+        let mut progress = |task: &Task| {
+            progress(&Job {
+                prompt: prompt.clone(),
+                task: task.clone(),
+                priority,
+                message_id: message_id.clone(),
+            })
+        };
+        let ended = match task {
+            Task::Reply { to } => {
+                tokio::select! {
+                    answer = async {
+                        let permit = scheduler.acquire(config.persona(&to).model(), priority).await?;
+                        progress(&Task::Reply { to: to.clone() });
+                        let delivered =
+                            reply(options, config, knowledge_base, &path, &to, &email, permit);
+                        delivered.await.map(|(_, answer)| answer)
+                    } => {
+                        match answer {
+                            Some(Ok(_)) => Ended::Done,
+                            Some(Err(err)) if is_transient(&err) => {
+                                Ended::Held(Task::Reply { to }, err.to_string())
+                            }
+                            Some(Err(err)) => Ended::Failed(err.to_string()),
+                            None => Ended::Cancelled(Task::Reply { to }),
+                        }
+                    }
+                    _ = wait_for(&mut cancelled) => Ended::Cancelled(Task::Reply { to }),
+                }
+            }
+            Task::Panel {
+                panel,
+                members,
+                answers,
+            } => {
+                let run = Run {
+                    options,
+                    config,
+                    knowledge_base,
+                    path: &path,
+                    scheduler,
+                    priority,
+                    cancelled: &mut cancelled,
+                    progress: &mut progress,
+                };
+                run.panel(&email, panel, members, answers).await
+            }
+            Task::Debate {
+                models,
+                rounds,
+                turn,
+                last,
+            } => {
+                let run = Run {
+                    options,
+                    config,
+                    knowledge_base,
+                    path: &path,
+                    scheduler,
+                    priority,
+                    cancelled: &mut cancelled,
+                    progress: &mut progress,
+                };
+                run.debate(models, rounds, turn, last).await
+            }
+        };
+        match ended {
+            Ended::Done => Outcome::Done,
+            Ended::Failed(reason) => Outcome::Failed(reason),
+            Ended::Held(task, reason) => Outcome::Held(
+                Job {
+                    prompt,
+                    task,
+                    priority,
+                    message_id,
+                },
+                reason,
+            ),
+            Ended::Cancelled(task) => Outcome::Cancelled(Job {
+                prompt,
+                task,
+                priority,
+                message_id,
+            }),
+        }
+    }
+}
+
+/////////////////////////////////////////////// tasks //////////////////////////////////////////////
+
+enum Ended {
+    Done,
+    Failed(String),
+    Held(Task, String),
+    Cancelled(Task),
+}
+
+// Everything a multi-step task needs to take its steps.
+struct Run<'a> {
+    options: &'a MaintainOptions,
+    config: &'a Config,
+    knowledge_base: &'a Path<'a>,
+    path: &'a Path<'a>,
+    scheduler: &'a Scheduler,
+    priority: u8,
+    cancelled: &'a mut watch::Receiver<bool>,
+    progress: &'a mut (dyn FnMut(&Task) + Send),
+}
+
+impl Run<'_> {
+    async fn panel(
+        self,
+        email: &[u8],
+        panel: String,
+        members: Vec<String>,
+        mut answers: Vec<(String, String)>,
+    ) -> Ended {
+        let mut pending = JoinSet::new();
+        let mut ids = std::collections::HashMap::new();
+        // Members say when they get their turn, so the panel counts as running from the first.
+        let (started, mut starts) = tokio::sync::mpsc::unbounded_channel();
+        for member in members.iter() {
+            if answers.iter().any(|(answered, _)| answered == member) {
+                continue;
+            }
+            let options = self.options.clone();
+            let config = self.config.clone();
+            let knowledge_base = self.knowledge_base.clone().into_owned();
+            let path = self.path.clone().into_owned();
+            let email = email.to_vec();
+            let to = member.clone();
+            let scheduler = self.scheduler.clone();
+            let priority = self.priority;
+            let started = started.clone();
+            let handle = pending.spawn(async move {
+                let Some(permit) = scheduler
+                    .acquire(config.persona(&to).model(), priority)
+                    .await
+                else {
+                    return (to, None);
+                };
+                let _ = started.send(());
+                let answer = reply(
+                    &options,
+                    &config,
+                    &knowledge_base,
+                    &path,
+                    &to,
+                    &email,
+                    permit,
+                )
+                .await
+                .map(|(_, answer)| answer);
+                (to, answer)
+            });
+            ids.insert(handle.id(), member.clone());
+        }
+        drop(started);
+        // Members the backend could not reach, or that never got a turn, are left out of `answers`
+        // so they run again later.
+        let mut held = None;
+        let mut closed = false;
+        let mut running = false;
+        loop {
+            tokio::select! {
+                Some(()) = starts.recv(), if !running => {
+                    running = true;
+                }
+                joined = pending.join_next() => match joined {
+                    Some(Ok((_, None))) => {
+                        closed = true;
+                        continue;
+                    }
+                    Some(Ok((_, Some(Err(e))))) if is_transient(&e) => {
+                        held = Some(e.to_string());
+                        continue;
+                    }
+                    Some(Ok((member, Some(Ok(answer))))) => answers.push((member, answer)),
+                    Some(Ok((member, Some(Err(e))))) => {
+                        answers.push((member, format!("error processing: {}", e)));
+                    }
+                    Some(Err(e)) => {
+                        if let Some(member) = ids.get(&e.id()) {
+                            answers.push((member.clone(), format!("error processing: {}", e)));
+                        }
+                    }
+                    None => break,
+                },
+                _ = wait_for(self.cancelled) => {
+                    pending.abort_all();
+                    return Ended::Cancelled(Task::Panel { panel, members, answers });
+                }
+            }
+            (self.progress)(&Task::Panel {
+                panel: panel.clone(),
+                members: members.clone(),
+                answers: answers.clone(),
+            });
+        }
+        if closed {
+            return Ended::Cancelled(Task::Panel {
+                panel,
+                members,
+                answers,
+            });
+        }
+        if let Some(reason) = held {
+            return Ended::Held(
+                Task::Panel {
+                    panel,
+                    members,
+                    answers,
+                },
+                reason,
+            );
+        }
+        // Compare the answers in the order the panel lists its members.
+        answers.sort_by_key(|(member, _)| members.iter().position(|m| m == member));
+        let comparison = match format_comparison(&panel, email, &answers) {
+            Ok(comparison) => comparison,
+            Err(error) => {
+                let failure = Failure {
+                    recipient: &panel,
+                    model: &members.join(", "),
+                    backend: &self.options.yammer.url(),
+                    attempts: 0,
+                    error: &error,
+                };
+                format_bounce(&failure, email)
+            }
+        };
+        match save_to_inbox(self.knowledge_base, comparison) {
+            Some(_) => Ended::Done,
+            None => Ended::Failed(format!("could not deliver the comparison for {}", panel)),
+        }
+    }
+
+    async fn debate(
+        self,
+        models: Vec<String>,
+        rounds: usize,
+        mut turn: usize,
+        mut last: Option<String>,
+    ) -> Ended {
+        let Run {
+            options,
+            config,
+            knowledge_base,
+            path,
+            scheduler,
+            priority,
+            cancelled,
+            progress,
+        } = self;
+        let inbox = knowledge_base.join(INBOX);
+        // Each turn answers the turn before it; the loop bounds the debate.
+        while turn < rounds * models.len() {
+            let message = match &last {
+                Some(last) => match maildir::find(&inbox, last) {
+                    Some(message) => message,
+                    None => {
+                        return Ended::Failed(format!("turn {} is no longer in {}", last, INBOX));
+                    }
+                },
+                None => path.clone().into_owned(),
+            };
+            let email = match std::fs::read(&message) {
+                Ok(email) => email,
+                Err(err) => {
+                    return Ended::Failed(format!("could not read {}: {}", message, err));
+                }
+            };
+            let model = &models[turn % models.len()];
+            let delivered = tokio::select! {
+                delivered = async {
+                    let permit = scheduler.acquire(config.persona(model).model(), priority).await?;
+                    progress(&Task::Debate {
+                        models: models.clone(),
+                        rounds,
+                        turn,
+                        last: last.clone(),
+                    });
+                    reply(options, config, knowledge_base, path, model, &email, permit).await
+                } => delivered,
+                _ = wait_for(cancelled) => {
+                    return Ended::Cancelled(Task::Debate { models, rounds, turn, last });
+                }
+            };
+            let Some(delivered) = delivered else {
+                return Ended::Cancelled(Task::Debate {
+                    models,
+                    rounds,
+                    turn,
+                    last,
+                });
+            };
+            let delivered = match delivered {
+                (_, Err(err)) if is_transient(&err) => {
+                    let task = Task::Debate {
+                        models,
+                        rounds,
+                        turn,
+                        last,
+                    };
+                    return Ended::Held(task, err.to_string());
+                }
+                (Some(delivered), Ok(_)) => delivered,
+                (_, Err(err)) => return Ended::Failed(err.to_string()),
+                (None, _) => {
+                    return Ended::Failed(format!("could not deliver turn {}", turn + 1));
+                }
+            };
+            last = Some(maildir::unique_part(delivered.basename().as_str()).to_string());
+            turn += 1;
+            progress(&Task::Debate {
+                models: models.clone(),
+                rounds,
+                turn,
+                last: last.clone(),
+            });
+        }
+        Ended::Done
+    }
+}
+
+/////////////////////////////////////////////// utils //////////////////////////////////////////////
+
+// Resolve once cancellation is requested.
+async fn wait_for(cancelled: &mut watch::Receiver<bool>) {
+    let _ = cancelled.wait_for(|cancelled| *cancelled).await;
+}
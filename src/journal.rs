@@ -0,0 +1,270 @@
+//! A crash-safe record of every job, so that a prompt is never moved to INBOX without a reply on the
+//! way.
+
+use std::io::Write;
+
+use utf8path::Path;
+
+use super::{Job, JOURNAL};
+
+/// How long the journal, and so `maildir-ai queue`, keeps a job after it fails.  Its bounce is in
+/// INBOX by then.
+pub const KEEP_FAILED: std::time::Duration = std::time::Duration::from_secs(7 * 24 * 60 * 60);
+
+/////////////////////////////////////////////// State //////////////////////////////////////////////
+
+/// Where a job is in its life.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    /// Waiting to run, either because it is new or because a shutdown interrupted it.
+    Pending,
+    /// Running now, or running when the daemon died.
+    Running,
+    /// Waiting for the backend to come back; `error` says what went wrong.
+    Held,
+    /// Finished.
+    Done,
+    /// Gave up; `error` says why.
+    Failed,
+    /// Stopped because its prompt was withdrawn.
+    Cancelled,
+}
+
+impl std::fmt::Display for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            State::Pending => write!(f, "pending"),
+            State::Running => write!(f, "running"),
+            State::Held => write!(f, "held"),
+            State::Done => write!(f, "done"),
+            State::Failed => write!(f, "failed"),
+            State::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/////////////////////////////////////////////// Entry //////////////////////////////////////////////
+
+/// The journal's record of one job.
+#[derive(Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Entry {
+    /// Unique within the journal:  the prompt's maildir name and the job's position among its jobs.
+    pub id: String,
+    /// The state of the job.
+    pub state: State,
+    /// The job, including how far along it is.
+    pub job: Job,
+    /// Why the job failed or is held.
+    #[serde(default)]
+    pub error: Option<String>,
+    /// How many times the job has been held for want of a backend.
+    #[serde(default)]
+    pub holds: u32,
+    /// When the job was created, in RFC 3339.
+    pub created: String,
+    /// When the entry was last written, in RFC 3339.
+    pub updated: String,
+}
+
+impl Entry {
+    /// A new pending entry for the `index`'th job of its prompt.
+    pub fn new(job: Job, index: usize) -> Self {
+        let now = chrono::Utc::now().to_rfc3339();
+        Self {
+            id: format!("{}-{}", job.prompt, index),
+            state: State::Pending,
+            job,
+            error: None,
+            holds: 0,
+            created: now.clone(),
+            updated: now,
+        }
+    }
+
+    /// True if the job failed more than [KEEP_FAILED] before `now`, or when is unknown.
+    pub fn expired(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        if self.state != State::Failed {
+            return false;
+        }
+        let Ok(updated) = chrono::DateTime::parse_from_rfc3339(&self.updated) else {
+            return true;
+        };
+        now.signed_duration_since(updated)
+            .to_std()
+            .map(|age| age > KEEP_FAILED)
+            .unwrap_or(false)
+    }
+}
+
+////////////////////////////////////////////// Journal /////////////////////////////////////////////
+
+/// The journal of a knowledge base:  one file per job, each replaced atomically on every change.
+#[derive(Clone, Debug)]
+pub struct Journal {
+    dir: Path<'static>,
+}
+
+impl Journal {
+    /// Open the journal of the knowledge base, creating it if need be.
+    pub fn open(knowledge_base: &Path<'_>) -> Result<Self, std::io::Error> {
+        let dir = knowledge_base.join(JOURNAL).into_owned();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Durably record `entry`, replacing any earlier record of the same job.
+    pub fn record(&self, entry: &mut Entry) -> Result<(), std::io::Error> {
+        entry.updated = chrono::Utc::now().to_rfc3339();
+        let tmp = self.dir.join(format!("{}.json.tmp", entry.id));
+        let path = self.dir.join(format!("{}.json", entry.id));
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(serde_json::to_string_pretty(entry)?.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &path)?;
+        std::fs::File::open(&self.dir)?.sync_all()
+    }
+
+    /// Record `entry`, logging rather than returning failure.  For use where there's no better
+    /// recourse than carrying on.
+    pub fn record_or_log(&self, entry: &mut Entry) {
+        if let Err(err) = self.record(entry) {
+            eprintln!(
+                "error: could not journal {} as {}: {}",
+                entry.id, entry.state, err
+            );
+        }
+    }
+
+    /// The entry with `id`.
+    pub fn entry(&self, id: &str) -> Result<Entry, std::io::Error> {
+        let contents = std::fs::read_to_string(self.dir.join(format!("{}.json", id)))?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    /// Every entry in the journal, oldest first.
+    pub fn entries(&self) -> Result<Vec<Entry>, std::io::Error> {
+        let mut entries = vec![];
+        for dirent in std::fs::read_dir(&self.dir)? {
+            let path = dirent?.path();
+            if path.extension().and_then(|x| x.to_str()) != Some("json") {
+                continue;
+            }
+            let contents = std::fs::read_to_string(&path)?;
+            match serde_json::from_str::<Entry>(&contents) {
+                Ok(entry) => entries.push(entry),
+                Err(err) => eprintln!("error: could not parse {}: {}", path.display(), err),
+            }
+        }
+        entries.sort_by(|lhs, rhs| (&lhs.created, &lhs.id).cmp(&(&rhs.created, &rhs.id)));
+        Ok(entries)
+    }
+
+    /// Forget the job with `id`, if the journal has it.
+    pub fn remove(&self, id: &str) -> Result<(), std::io::Error> {
+        match std::fs::remove_file(self.dir.join(format!("{}.json", id))) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Forget the jobs that are done or cancelled, and those that failed long enough ago to have
+    /// expired.
+    pub fn prune(&self) -> Result<(), std::io::Error> {
+        self.prune_as_of(chrono::Utc::now())
+    }
+
+    fn prune_as_of(&self, now: chrono::DateTime<chrono::Utc>) -> Result<(), std::io::Error> {
+        for entry in self.entries()? {
+            if matches!(entry.state, State::Done | State::Cancelled) || entry.expired(now) {
+                self.remove(&entry.id)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job::Task;
+    use crate::tests::scratch_knowledge_base;
+
+    fn entry(name: &str, index: usize) -> Entry {
+        let path = Path::from(format!("/kb/Sent/cur/{}:2,S", name));
+        let task = Task::Reply {
+            to: "llama3@rave".to_string(),
+        };
+        Entry::new(
+            Job::new(&path, b"Message-ID: <p@example.org>\n\n", task),
+            index,
+        )
+    }
+
+    #[test]
+    fn entries_are_recorded_and_replaced() {
+        let kb = scratch_knowledge_base("journal");
+        let journal = Journal::open(&kb).unwrap();
+        let mut first = entry("1.a", 0);
+        let mut second = entry("1.a", 1);
+        journal.record(&mut first).unwrap();
+        journal.record(&mut second).unwrap();
+        first.state = State::Running;
+        journal.record(&mut first).unwrap();
+        let entries = journal.entries().unwrap();
+        assert_eq!(
+            vec!["1.a-0", "1.a-1"],
+            entries.iter().map(|e| e.id.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!(first, entries[0]);
+        assert_eq!(
+            Some("<p@example.org>"),
+            entries[0].job.message_id.as_deref()
+        );
+        assert_eq!(first, journal.entry("1.a-0").unwrap());
+        journal.remove("1.a-1").unwrap();
+        journal.remove("1.a-1").unwrap();
+        assert_eq!(1, journal.entries().unwrap().len());
+        std::fs::remove_dir_all(&kb).unwrap();
+    }
+
+    #[test]
+    fn prune_forgets_finished_jobs_and_old_failures() {
+        let kb = scratch_knowledge_base("prune");
+        let journal = Journal::open(&kb).unwrap();
+        let states = [
+            State::Pending,
+            State::Running,
+            State::Held,
+            State::Done,
+            State::Failed,
+            State::Cancelled,
+        ];
+        for (index, state) in states.into_iter().enumerate() {
+            let mut entry = entry("1.a", index);
+            entry.state = state;
+            journal.record(&mut entry).unwrap();
+        }
+        journal.prune().unwrap();
+        let states = |journal: &Journal| {
+            journal
+                .entries()
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.state)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec![State::Pending, State::Running, State::Held, State::Failed],
+            states(&journal)
+        );
+        let later = chrono::Utc::now() + chrono::TimeDelta::from_std(KEEP_FAILED).unwrap();
+        journal.prune_as_of(later).unwrap();
+        assert_eq!(
+            vec![State::Pending, State::Running, State::Held],
+            states(&journal)
+        );
+        std::fs::remove_dir_all(&kb).unwrap();
+    }
+}
@@ -1,14 +1,46 @@
+use std::collections::HashMap;
 use std::str::FromStr;
-use std::time::SystemTime;
+use std::sync::{Arc, Mutex};
 
+use tokio::task::JoinSet;
 use utf8path::Path;
-use yammer::{FieldWriteAccumulator, GenerateRequest, Request, RequestOptions};
+use yammer::{Accumulator, ChatMessage, ChatRequest, GenerateRequest, Request, RequestOptions};
+
+mod address;
+mod backlog;
+mod bounce;
+mod clean;
+mod config;
+mod inflight;
+mod job;
+mod journal;
+mod maildir;
+mod mime;
+mod rfc2047;
+mod scheduler;
+mod thread;
+mod watch;
+
+pub use address::{Address, AddressList, Mailbox};
+pub use backlog::Backlog;
+pub use bounce::{format_bounce, Failure, MAILER_DAEMON};
+pub use clean::clean_prompt;
+pub use config::{Config, Panel, Persona, Retry, DEFAULT_CONFIG};
+pub use inflight::{InFlight, Registration};
+pub use job::{Job, Outcome, Task};
+pub use journal::{Entry, Journal, State};
+pub use maildir::deliver;
+pub use mime::{html_to_text, Attachment, Message, Part};
+pub use scheduler::{Permit, Scheduler};
+pub use thread::Thread;
+pub use watch::Watcher;
 
 ///////////////////////////////////////////// constants ////////////////////////////////////////////
 
 const ARCHIVE: &str = "Archive";
 const DRAFTS: &str = "Drafts";
 const INBOX: &str = "INBOX";
+const QUARANTINE: &str = "Quarantine";
 const SENT: &str = "Sent";
 const TRASH: &str = "Trash";
 
@@ -16,11 +48,37 @@ const CUR: &str = "cur";
 const NEW: &str = "new";
 const TMP: &str = "tmp";
 
+const CONFIG: &str = ".maildir-ai.toml";
+const JOURNAL: &str = ".maildir-ai.journal";
+
+/// Recipients listed here get the prompt but are left out of the replies of other recipients.
+pub const X_AI_BCC: &str = "X-AI-Bcc";
+/// Mail to two or more models with this header set to N makes the models debate for N rounds.
+pub const X_AI_DEBATE: &str = "X-AI-Debate";
+/// Every reply is stamped with this header so that the daemon never answers its own mail.
+pub const X_LOOP: &str = "X-Loop";
+/// The number of replies between a reply and the prompt that started its thread.
+pub const X_AI_HOPS: &str = "X-AI-Hops";
+/// Quarantine reports name the message they explain in this header.
+pub const X_AI_QUARANTINED: &str = "X-AI-Quarantined";
+/// Prompts with a lower X-Priority, from 1 (highest) to 5 (lowest), are answered first.
+pub const X_PRIORITY: &str = "X-Priority";
+/// The priority of prompts without an X-Priority.
+pub const NORMAL_PRIORITY: u8 = 3;
+
+const LOOP_TOKEN: &str = "maildir-ai";
+/// The most message IDs a reply carries in References.
+const MAX_REFERENCES: usize = 20;
+/// How often to check whether the backend is back during an outage.
+const PROBE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+/// How often to forget finished jobs while maintain runs.
+const PRUNE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
 /////////////////////////////////////////////// init ///////////////////////////////////////////////
 
 /// Initialize a new maildir-ai database.
 pub fn init(knowledge_base: &utf8path::Path<'_>, real_name: &str) -> Result<(), std::io::Error> {
-    for level1 in &[ARCHIVE, DRAFTS, INBOX, SENT, TRASH] {
+    for level1 in &[ARCHIVE, DRAFTS, INBOX, QUARANTINE, SENT, TRASH] {
         std::fs::create_dir_all(knowledge_base.join(*level1).join(CUR))?;
         std::fs::create_dir_all(knowledge_base.join(*level1).join(NEW))?;
         std::fs::create_dir_all(knowledge_base.join(*level1).join(TMP))?;
@@ -58,7 +116,7 @@ set mask="!^\\.[^.]"
 
 macro index,pager a '<save-message>+Archive<enter><enter>'
 
-mailboxes `echo -n "+ "; find {knowledge_base} -maxdepth 1 -type d -name ".*" -printf "+'%f' "`
+mailboxes `echo -n "+ "; find {knowledge_base} -maxdepth 1 -type d -name ".*" ! -name "{JOURNAL}" -printf "+'%f' "`
 
 ################################### Browsing ###################################
 
@@ -85,134 +143,1148 @@ set mark_old=no
 "#
     );
     std::fs::write(knowledge_base.join(".muttrc"), muttrc)?;
+    if !knowledge_base.join(CONFIG).into_std().exists() {
+        std::fs::write(knowledge_base.join(CONFIG), DEFAULT_CONFIG)?;
+    }
     Ok(())
 }
 
 ////////////////////////////////////////// MaintainOptions /////////////////////////////////////////
 
 /// The options for maintaining a knowledge base.
-#[derive(Clone, Debug, Default, Eq, PartialEq, arrrg_derive::CommandLine)]
+#[derive(Clone, Debug, Eq, PartialEq, arrrg_derive::CommandLine)]
 pub struct MaintainOptions {
     #[arrrg(nested)]
     yammer: RequestOptions,
+    #[arrrg(flag, "Log Sent mail that is archived because no model was addressed.")]
+    log_skipped: bool,
+    #[arrrg(
+        optional,
+        "Seconds to wait for replies in flight when shutting down.",
+        "SECONDS"
+    )]
+    shutdown_deadline: u64,
+}
+
+impl Default for MaintainOptions {
+    fn default() -> Self {
+        Self {
+            yammer: RequestOptions::default(),
+            log_skipped: false,
+            shutdown_deadline: 30,
+        }
+    }
 }
 
 ///////////////////////////////////////////// maintain /////////////////////////////////////////////
 
 /// Maintain a knowledge base.  This will try hard to not fail.
+///
+/// Every job is journaled before its prompt leaves Sent, and jobs the journal shows as pending or
+/// running are resumed on startup, so a crash never loses a prompt.  Runs until SIGINT or SIGTERM.
+/// It then stops taking new prompts and waits up to the shutdown deadline for the jobs in flight;
+/// jobs that do not finish in time go back to pending in the journal.
 pub async fn maintain(options: &MaintainOptions, knowledge_base: &utf8path::Path<'_>) {
-    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
-    tx.send(tokio::task::spawn(async move {})).await.unwrap();
-    let _reap = tokio::task::spawn(async move {
-        while let Some(handle) = rx.recv().await {
-            let _ = handle.await;
+    let (cancel, cancelled) = tokio::sync::watch::channel(false);
+    let mut tasks = JoinSet::new();
+    let context = loop {
+        match resume(options, knowledge_base, &mut tasks, &cancelled) {
+            Ok(context) => break context,
+            Err(err) => {
+                eprintln!("error: could not resume the journal: {}", err);
+                tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+            }
         }
-    });
+    };
+    // Prompts arrive in Sent; they are withdrawn by leaving INBOX, usually for Trash.
+    let mut watcher = Watcher::new(&[
+        knowledge_base.join(SENT).join(CUR),
+        knowledge_base.join(INBOX).join(CUR),
+        knowledge_base.join(INBOX).join(NEW),
+        knowledge_base.join(TRASH).join(CUR),
+        knowledge_base.join(TRASH).join(NEW),
+    ]);
+    // Only what reaches Trash from now on can withdraw a prompt.
+    let seen = Arc::new(Mutex::new(Seen::new(knowledge_base)));
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut probe = tokio::time::interval(PROBE_INTERVAL);
+    probe.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut prune =
+        tokio::time::interval_at(tokio::time::Instant::now() + PRUNE_INTERVAL, PRUNE_INTERVAL);
+    prune.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
     loop {
-        if let Err(e) = maintain_one(options, knowledge_base).await {
+        if let Err(e) = maintain_one(options, knowledge_base, &context, &mut tasks).await {
             eprintln!("error: {}", e);
-            std::thread::sleep(std::time::Duration::from_secs(60));
+            tokio::select! {
+                _ = tokio::time::sleep(std::time::Duration::from_secs(60)) => continue,
+                _ = &mut shutdown => break,
+            }
+        }
+        while let Some(joined) = tasks.try_join_next_with_id() {
+            reap(options, knowledge_base, &context, joined);
+        }
+        withdraw_prompts(knowledge_base, &context, &seen).await;
+        tokio::select! {
+            _ = watcher.changed() => {}
+            // Jobs may be held at any time, so the outage is checked on every tick.
+            _ = probe.tick() => {
+                if !context.backlog.is_down() {
+                    continue;
+                }
+                if let Err(err) = probe_backend(options).await {
+                    eprintln!("backend still down: {}", err);
+                    continue;
+                }
+                let held = context.backlog.release();
+                eprintln!("backend is back; releasing {} held jobs", held.len());
+                let config = match Config::load(knowledge_base) {
+                    Ok(config) => config,
+                    Err(err) => {
+                        eprintln!("error: {}", err);
+                        Config::default()
+                    }
+                };
+                for entry in held.into_iter() {
+                    spawn_job(options, &config, knowledge_base, &context, &mut tasks, entry);
+                }
+            }
+            _ = prune.tick() => {
+                if let Err(err) = context.journal.prune() {
+                    eprintln!("error: could not prune the journal: {}", err);
+                }
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+    drain(options, knowledge_base, &context, tasks, cancel).await;
+}
+
+/// What every job of one run of maintain shares.
+#[derive(Clone)]
+struct Context {
+    journal: Journal,
+    backlog: Backlog,
+    scheduler: Scheduler,
+    in_flight: InFlight,
+    // The journal entry of each job's task, so a job whose task panics can still be failed.
+    spawned: Arc<Mutex<HashMap<tokio::task::Id, String>>>,
+    cancelled: tokio::sync::watch::Receiver<bool>,
+}
+
+/// Open the journal, forget finished jobs, and restart the jobs that were pending, running, or held.
+fn resume(
+    options: &MaintainOptions,
+    knowledge_base: &Path<'_>,
+    tasks: &mut JoinSet<()>,
+    cancelled: &tokio::sync::watch::Receiver<bool>,
+) -> Result<Context, std::io::Error> {
+    let journal = Journal::open(knowledge_base)?;
+    journal.prune()?;
+    let config = Config::load(knowledge_base)?;
+    let context = Context {
+        journal,
+        backlog: Backlog::default(),
+        scheduler: Scheduler::new(config.max_requests, config.max_requests_per_model),
+        in_flight: InFlight::default(),
+        spawned: Arc::default(),
+        cancelled: cancelled.clone(),
+    };
+    let entries = context
+        .journal
+        .entries()?
+        .into_iter()
+        .filter(|entry| matches!(entry.state, State::Pending | State::Running | State::Held))
+        .collect::<Vec<_>>();
+    if !entries.is_empty() {
+        eprintln!("resuming {} jobs", entries.len());
+        for entry in entries.into_iter() {
+            spawn_job(options, &config, knowledge_base, &context, tasks, entry);
+        }
+    }
+    Ok(context)
+}
+
+/// Wait for the jobs in flight until they finish or the deadline passes.  A second signal skips
+/// the wait.  Jobs still waiting for their turn go back to pending right away.
+async fn drain(
+    options: &MaintainOptions,
+    knowledge_base: &Path<'_>,
+    context: &Context,
+    mut tasks: JoinSet<()>,
+    cancel: tokio::sync::watch::Sender<bool>,
+) {
+    context.scheduler.close();
+    let deadline = std::time::Duration::from_secs(options.shutdown_deadline);
+    while let Some(joined) = tasks.try_join_next_with_id() {
+        reap(options, knowledge_base, context, joined);
+    }
+    if !tasks.is_empty() {
+        eprintln!(
+            "shutting down: waiting up to {}s for {} jobs; signal again to stop now",
+            deadline.as_secs(),
+            tasks.len()
+        );
+    }
+    let timeout = tokio::time::sleep(deadline);
+    tokio::pin!(timeout);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            joined = tasks.join_next_with_id() => match joined {
+                Some(joined) => reap(options, knowledge_base, context, joined),
+                None => break,
+            },
+            _ = &mut timeout, if !*cancel.borrow() => {
+                let _ = cancel.send(true);
+            }
+            _ = &mut shutdown, if !*cancel.borrow() => {
+                let _ = cancel.send(true);
+            }
+        }
+    }
+}
+
+/// Resolve when the process is asked to stop with SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+    }
+    if tokio::signal::ctrl_c().await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Run the job of `entry`, journaling it as it waits its turn, starts, progresses, and ends.  While
+/// the backend is down the job goes straight to the backlog instead.  Withdrawing the prompt stops
+/// the job wherever it is and leaves a note in its place.
+fn spawn_job(
+    options: &MaintainOptions,
+    config: &Config,
+    knowledge_base: &Path<'_>,
+    context: &Context,
+    tasks: &mut JoinSet<()>,
+    mut entry: Entry,
+) {
+    let options = options.clone();
+    let config = config.clone();
+    let knowledge_base = knowledge_base.clone().into_owned();
+    let Context {
+        journal,
+        backlog,
+        scheduler,
+        in_flight,
+        spawned: _,
+        cancelled,
+    } = context.clone();
+    let entry_id = entry.id.clone();
+    let task = tasks.spawn(async move {
+        if backlog.is_down() {
+            entry.state = State::Held;
+            journal.record_or_log(&mut entry);
+            backlog.hold(entry);
+            return;
+        }
+        // The job is queued until it gets its first turn with the scheduler.
+        entry.state = State::Pending;
+        journal.record_or_log(&mut entry);
+        // Keep the prompt's headers to thread the note under it should it be withdrawn and deleted.
+        let job = entry.job.clone();
+        let mut registration = job.message_id.as_ref().map(|msg_id| {
+            let headers = job
+                .find_prompt(&knowledge_base)
+                .and_then(|path| std::fs::read(path).ok())
+                .map(|email| mime::parse_headers(&email))
+                .unwrap_or_default();
+            (in_flight.register(msg_id, &job.prompt), headers)
+        });
+        let mut progress = |job: &Job| {
+            entry.state = State::Running;
+            entry.job = job.clone();
+            entry.error = None;
+            journal.record_or_log(&mut entry);
+        };
+        let run = job.run(
+            &options,
+            &config,
+            &knowledge_base,
+            &scheduler,
+            cancelled,
+            &mut progress,
+        );
+        let outcome = match &mut registration {
+            Some((registration, _)) => tokio::select! {
+                outcome = run => Some(outcome),
+                _ = registration.withdrawn() => None,
+            },
+            None => Some(run.await),
+        };
+        let Some(outcome) = outcome else {
+            eprintln!("job {} cancelled: its prompt was withdrawn", entry.id);
+            entry.state = State::Cancelled;
+            journal.record_or_log(&mut entry);
+            if let Some((_, headers)) = &registration {
+                report_withdrawn(&knowledge_base, &entry.job, headers);
+            }
+            return;
+        };
+        match outcome {
+            Outcome::Done => {
+                backlog.recovered();
+                entry.state = State::Done;
+            }
+            Outcome::Failed(reason) => {
+                eprintln!("job {} failed: {}", entry.id, reason);
+                entry.state = State::Failed;
+                entry.error = Some(reason);
+            }
+            Outcome::Held(job, reason) if entry.holds + 1 >= config.max_holds => {
+                // The backend answers probes but not this job, so stop cycling through the backlog.
+                entry.holds += 1;
+                let error = std::io::Error::other(format!(
+                    "gave up after the job was held {} times: {}",
+                    entry.holds, reason
+                ));
+                eprintln!("job {} failed: {}", entry.id, error);
+                if let Err(err) =
+                    job.give_up(&options, &config, &knowledge_base, entry.holds, &error)
+                {
+                    eprintln!("error: job {}: {}", entry.id, err);
+                }
+                entry.state = State::Failed;
+                entry.job = job;
+                entry.error = Some(error.to_string());
+            }
+            Outcome::Held(job, reason) => {
+                eprintln!("job {} held: {}", entry.id, reason);
+                entry.holds += 1;
+                entry.state = State::Held;
+                entry.job = job;
+                entry.error = Some(reason.clone());
+                journal.record_or_log(&mut entry);
+                if backlog.hold(entry) {
+                    report_outage(&options, &config, &knowledge_base, &reason);
+                }
+                return;
+            }
+            Outcome::Cancelled(job) => {
+                entry.state = State::Pending;
+                entry.job = job;
+            }
+        }
+        journal.record_or_log(&mut entry);
+    });
+    context
+        .spawned
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .insert(task.id(), entry_id);
+}
+
+/// Account for a job's task that has ended.  A task that panicked never finished its job in the
+/// journal, where it would be resumed on every restart, so fail it there and bounce its prompt.
+fn reap(
+    options: &MaintainOptions,
+    knowledge_base: &Path<'_>,
+    context: &Context,
+    joined: Result<(tokio::task::Id, ()), tokio::task::JoinError>,
+) {
+    let id = match &joined {
+        Ok((id, ())) => *id,
+        Err(err) => err.id(),
+    };
+    let entry_id = context
+        .spawned
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .remove(&id);
+    let Err(err) = joined else {
+        return;
+    };
+    eprintln!("error: job failed: {}", err);
+    let (Some(entry_id), true) = (entry_id, err.is_panic()) else {
+        return;
+    };
+    let mut entry = match context.journal.entry(&entry_id) {
+        Ok(entry) => entry,
+        Err(err) => {
+            eprintln!(
+                "error: could not read job {} from the journal: {}",
+                entry_id, err
+            );
+            return;
+        }
+    };
+    let config = match Config::load(knowledge_base) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            Config::default()
+        }
+    };
+    let error = std::io::Error::other(format!("the job crashed: {}", err));
+    if let Err(err) = entry
+        .job
+        .give_up(options, &config, knowledge_base, entry.holds, &error)
+    {
+        eprintln!("error: job {}: {}", entry.id, err);
+    }
+    entry.state = State::Failed;
+    entry.error = Some(error.to_string());
+    context.journal.record_or_log(&mut entry);
+}
+
+///////////////////////////////////////////// withdraw /////////////////////////////////////////////
+
+/// Cancel the jobs whose prompt the user has withdrawn, either by moving it to Trash or by deleting
+/// it, whether they are running or held.
+///
+/// mutt's `d` copies the prompt to Trash and deletes it from INBOX only when the folder is synced,
+/// so the copy in Trash is what withdraws it first.  A prompt that is gone from INBOX is withdrawn
+/// unless it was merely filed in another folder.
+async fn withdraw_prompts(knowledge_base: &Path<'_>, context: &Context, seen: &Arc<Mutex<Seen>>) {
+    let idle = context.in_flight.is_empty() && context.backlog.is_empty();
+    let prompts = context
+        .in_flight
+        .prompts()
+        .into_iter()
+        .chain(context.backlog.prompts())
+        .collect::<Vec<_>>();
+    // Looking for the prompts reads the knowledge base, so keep it off the executor.
+    let withdrawn = {
+        let knowledge_base = knowledge_base.clone().into_owned();
+        let seen = Arc::clone(seen);
+        tokio::task::spawn_blocking(move || {
+            seen.lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .withdrawn(&knowledge_base, idle, &prompts)
+        })
+        .await
+    };
+    let withdrawn = match withdrawn {
+        Ok(withdrawn) => withdrawn,
+        Err(err) => {
+            eprintln!("error: could not look for withdrawn prompts: {}", err);
+            return;
+        }
+    };
+    for (msg_id, how) in withdrawn.into_iter() {
+        // Running jobs leave their own note; held jobs have no task to do it, so it is done here.
+        let running = context.in_flight.withdraw(&msg_id);
+        let held = context.backlog.withdraw(&msg_id);
+        for mut entry in held.iter().cloned() {
+            eprintln!("job {} cancelled: its prompt was withdrawn", entry.id);
+            let headers = entry
+                .job
+                .find_prompt(knowledge_base)
+                .and_then(|path| mime::read_headers(path).ok())
+                .unwrap_or_default();
+            entry.state = State::Cancelled;
+            context.journal.record_or_log(&mut entry);
+            report_withdrawn(knowledge_base, &entry.job, &headers);
+        }
+        if running || !held.is_empty() {
+            eprintln!("withdrawn: {} {}", msg_id, how);
+        }
+    }
+}
+
+/// What has been seen of the knowledge base while looking for withdrawn prompts, so that each
+/// message's headers are read once rather than on every look.
+#[derive(Debug, Default)]
+struct Seen {
+    // The names of the messages in Trash.
+    trashed: std::collections::HashSet<String>,
+    // The Message-ID of every message filed in a folder other than Trash.
+    filed: HashMap<Path<'static>, Option<String>>,
+}
+
+impl Seen {
+    /// Start from what is in Trash now, so that only what reaches it later withdraws a prompt.
+    fn new(knowledge_base: &Path<'_>) -> Self {
+        let trashed = list_messages(&knowledge_base.join(TRASH))
+            .into_iter()
+            .map(|path| path.basename().to_string())
+            .collect();
+        Self {
+            trashed,
+            filed: HashMap::new(),
         }
-        std::thread::sleep(std::time::Duration::from_secs(1));
+    }
+
+    /// The Message-IDs of the `prompts` that have been withdrawn, with how.  The folders are only
+    /// searched when a prompt has left INBOX.
+    fn withdrawn(
+        &mut self,
+        knowledge_base: &Path<'_>,
+        idle: bool,
+        prompts: &[(String, String)],
+    ) -> Vec<(String, String)> {
+        let mut withdrawn = vec![];
+        for path in list_messages(&knowledge_base.join(TRASH)) {
+            if !self.trashed.insert(path.basename().to_string()) || idle {
+                continue;
+            }
+            if let Some(msg_id) = message_id_of(&path) {
+                withdrawn.push((msg_id, format!("was moved to {}", TRASH)));
+            }
+        }
+        let gone = prompts
+            .iter()
+            .filter(|(_, name)| maildir::find(&knowledge_base.join(INBOX), name).is_none())
+            .collect::<Vec<_>>();
+        if gone.is_empty() {
+            return withdrawn;
+        }
+        self.refresh(knowledge_base);
+        for (msg_id, _) in gone.into_iter() {
+            let filed = self
+                .filed
+                .values()
+                .any(|filed| filed.as_deref() == Some(msg_id.as_str()));
+            if !filed {
+                withdrawn.push((msg_id.clone(), "was deleted".to_string()));
+            }
+        }
+        withdrawn
+    }
+
+    /// Bring the Message-IDs of the filed messages up to date, reading only the new ones.
+    fn refresh(&mut self, knowledge_base: &Path<'_>) {
+        let paths = list_maildirs(knowledge_base)
+            .into_iter()
+            .filter(|maildir| maildir.basename().as_str() != TRASH)
+            .flat_map(|maildir| list_messages(&maildir))
+            .collect::<std::collections::HashSet<_>>();
+        self.filed.retain(|path, _| paths.contains(path));
+        for path in paths.into_iter() {
+            if let std::collections::hash_map::Entry::Vacant(entry) = self.filed.entry(path) {
+                let msg_id = message_id_of(entry.key());
+                entry.insert(msg_id);
+            }
+        }
+    }
+}
+
+/// Every folder of the knowledge base, including those the user made.
+fn list_maildirs(knowledge_base: &Path<'_>) -> Vec<Path<'static>> {
+    let Ok(dir) = std::fs::read_dir(knowledge_base) else {
+        return vec![];
+    };
+    let mut maildirs = vec![];
+    for dirent in dir.flatten() {
+        let Ok(path) = Path::try_from(dirent.path()) else {
+            continue;
+        };
+        if path.join(CUR).into_std().is_dir() {
+            maildirs.push(path.into_owned());
+        }
+    }
+    maildirs
+}
+
+/// Every message in the cur/ and new/ of `maildir`.
+fn list_messages(maildir: &Path<'_>) -> Vec<Path<'static>> {
+    let mut messages = vec![];
+    for level2 in &[CUR, NEW] {
+        let Ok(dir) = std::fs::read_dir(maildir.join(*level2)) else {
+            continue;
+        };
+        for dirent in dir.flatten() {
+            if let Ok(path) = Path::try_from(dirent.path()) {
+                messages.push(path.into_owned());
+            }
+        }
+    }
+    messages
+}
+
+/// The first Message-ID of the message at `path`, if it can be read.
+fn message_id_of(path: &Path<'_>) -> Option<String> {
+    mime::read_headers(path)
+        .ok()?
+        .iter()
+        .find_map(|header| match header {
+            Header::MessageID(x) => thread::split_msg_ids(x).into_iter().next(),
+            _ => None,
+        })
+}
+
+/// Leave a note in the thread of the prompt with `headers` that `job` was cancelled.
+fn report_withdrawn(knowledge_base: &Path<'_>, job: &Job, headers: &[Header]) {
+    let subject = headers
+        .iter()
+        .find_map(|header| match header {
+            Header::Subject(x) => Some(x.clone()),
+            _ => None,
+        })
+        .unwrap_or_default();
+    let mut note = notice_headers(format!("Cancelled: {}", subject).trim_end().to_string());
+    if let Some(msg_id) = &job.message_id {
+        note.push(Header::InReplyTo(msg_id.clone()));
+        note.push(Header::References(
+            references(headers, Some(msg_id)).join(" "),
+        ));
+    }
+    note.push(Header::Other(X_LOOP.to_string(), LOOP_TOKEN.to_string()));
+    note.push(Header::MimeVersion("1.0".to_string()));
+    note.push(Header::ContentType(
+        ParameterizedValue::new("text/plain").with_param("charset", "utf-8"),
+    ));
+    note.push(Header::ContentTransferEncoding("8bit".to_string()));
+    let note = format!(
+        "{}\n\nmaildir-ai cancelled this job because its prompt was withdrawn:\n\n    {}\n\n\
+         Replies already delivered are kept.\n",
+        Header::to_block(&note),
+        job.task,
+    );
+    save_to_inbox(knowledge_base, note);
+}
+
+////////////////////////////////////////////// outage //////////////////////////////////////////////
+
+/// Tell the user, once per outage, that the backend is down and prompts are waiting for it.
+fn report_outage(
+    options: &MaintainOptions,
+    config: &Config,
+    knowledge_base: &Path<'_>,
+    reason: &str,
+) {
+    let url = options.yammer.url();
+    let mut notice = notice_headers(format!("{} is unreachable", url));
+    notice.push(Header::MimeVersion("1.0".to_string()));
+    notice.push(Header::ContentType(
+        ParameterizedValue::new("text/plain").with_param("charset", "utf-8"),
+    ));
+    notice.push(Header::ContentTransferEncoding("8bit".to_string()));
+    let notice = format!(
+        "{}\n\nmaildir-ai could not reach the model backend at {} and gave up retrying:\n\n    {}\n\n\
+         Prompts are held in a backlog until it is reachable again and will be answered then.  \
+         A prompt held {} times is bounced instead.  This is the only notice for this outage.\n",
+        Header::to_block(&notice),
+        url,
+        reason,
+        config.max_holds,
+    );
+    save_to_inbox(knowledge_base, notice);
+}
+
+/// Check that the backend answers at all.  Any answer that is not itself a transient failure will do.
+async fn probe_backend(options: &MaintainOptions) -> Result<(), std::io::Error> {
+    let request = Request::tags(options.yammer.clone())?;
+    let mut acc = ResponseAccumulator::default();
+    match tokio::time::timeout(PROBE_INTERVAL, request.accumulate(&mut acc)).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(err)) => match backend_error(err) {
+            err if is_transient(&err) => Err(err),
+            _ => Ok(()),
+        },
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "the backend did not answer",
+        )),
     }
 }
 
 async fn maintain_one(
     options: &MaintainOptions,
     knowledge_base: &utf8path::Path<'_>,
+    context: &Context,
+    tasks: &mut JoinSet<()>,
 ) -> Result<(), std::io::Error> {
+    let config = Config::load(knowledge_base)?;
+    context
+        .scheduler
+        .set_limits(config.max_requests, config.max_requests_per_model);
     for dirent in std::fs::read_dir(knowledge_base.join(SENT).join(CUR))? {
         let dirent = dirent?;
-        let path = Path::try_from(dirent.path())
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-        if path.into_std().is_file() {
-            let email = std::fs::read_to_string(&path)?;
-            let Some(to) = extract_to(&email) else {
+        let path = match Path::try_from(dirent.path()) {
+            Ok(path) => path,
+            Err(err) => {
+                eprintln!("error: skipping {}: {}", dirent.path().display(), err);
+                continue;
+            }
+        };
+        if !path.clone().into_std().is_file() {
+            continue;
+        }
+        // One bad message must not hold up the rest, so a message that cannot be answered is
+        // quarantined.  When maildir-ai itself fails, the message stays in Sent to be tried again.
+        match dispatch(options, &config, knowledge_base, context, tasks, &path) {
+            Ok(()) => {}
+            Err(Undispatched::Refused(reason)) => {
+                eprintln!("quarantining: {} {}", path, reason);
+                let email = std::fs::read(&path).unwrap_or_default();
+                if let Err(err) = quarantine(knowledge_base, &path, &email, &reason) {
+                    eprintln!("error: could not quarantine {}: {}", path, err);
+                }
+            }
+            Err(Undispatched::Failed(err)) => {
+                eprintln!("error: leaving {} in {}: {}", path, SENT, err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Why a message in Sent was not dispatched.
+#[derive(Debug)]
+enum Undispatched {
+    /// The message cannot be answered, for a reason phrased to follow "because".
+    Refused(String),
+    /// maildir-ai could not do its part.
+    Failed(std::io::Error),
+}
+
+impl From<std::io::Error> for Undispatched {
+    fn from(err: std::io::Error) -> Self {
+        Self::Failed(err)
+    }
+}
+
+/// Work out what the message at `path` asks for, journal the jobs, move it to INBOX, and start the
+/// jobs.  If the jobs cannot be journaled or the message cannot be moved, the jobs are forgotten and
+/// the message is left where it was.
+fn dispatch(
+    options: &MaintainOptions,
+    config: &Config,
+    knowledge_base: &utf8path::Path<'_>,
+    context: &Context,
+    tasks: &mut JoinSet<()>,
+    path: &Path<'_>,
+) -> Result<(), Undispatched> {
+    let email = std::fs::read(path)
+        .map_err(|err| Undispatched::Refused(format!("it could not be read: {}", err)))?;
+    if let Some(reason) = refuse(config, &email) {
+        return Err(Undispatched::Refused(reason));
+    }
+    let to = extract_recipients(&email);
+    if to.is_empty() {
+        return Err(Undispatched::Refused(format!(
+            "it has no To, Cc, or {} recipients",
+            X_AI_BCC
+        )));
+    }
+    let mut models = vec![];
+    let mut panels = vec![];
+    for address in to.mailboxes().map(|mailbox| mailbox.address.clone()) {
+        if let Some(panel) = config.panel(&address) {
+            panels.push((address, panel.members.clone()));
+        } else if config.is_model(&address) {
+            models.push(address);
+        }
+    }
+    // Panels deliver the individual replies of their members, so don't answer twice.
+    models.retain(|model| {
+        !panels.iter().any(|(_, members)| {
+            members
+                .iter()
+                .any(|member| member.eq_ignore_ascii_case(model))
+        })
+    });
+    if models.is_empty() && panels.is_empty() {
+        if options.log_skipped {
+            eprintln!("skipping: {} addresses no model", path);
+        }
+        std::fs::rename(
+            path,
+            knowledge_base.join(ARCHIVE).join(CUR).join(path.basename()),
+        )?;
+        return Ok(());
+    }
+    let mut jobs = vec![];
+    let rounds = debate_rounds(&email).map(|n| n.min(config.max_debate_rounds));
+    if let (Some(rounds), true) = (rounds, models.len() >= 2) {
+        jobs.push(Task::Debate {
+            models: std::mem::take(&mut models),
+            rounds,
+            turn: 0,
+            last: None,
+        });
+    }
+    for to in models.into_iter() {
+        jobs.push(Task::Reply { to });
+    }
+    for (panel, members) in panels.into_iter() {
+        jobs.push(Task::Panel {
+            panel,
+            members,
+            answers: vec![],
+        });
+    }
+    // Journal the jobs before the prompt leaves Sent so that a crash cannot lose them.
+    let mut entries = jobs
+        .into_iter()
+        .enumerate()
+        .map(|(index, task)| Entry::new(Job::new(path, &email, task), index))
+        .collect::<Vec<_>>();
+    let journaled = entries
+        .iter_mut()
+        .try_for_each(|entry| context.journal.record(entry))
+        .and_then(|()| {
+            std::fs::rename(
+                path,
+                knowledge_base.join(INBOX).join(CUR).join(path.basename()),
+            )
+        });
+    if let Err(err) = journaled {
+        for entry in entries.iter() {
+            if let Err(err) = context.journal.remove(&entry.id) {
+                eprintln!("error: could not forget job {}: {}", entry.id, err);
+            }
+        }
+        return Err(err.into());
+    }
+    for entry in entries.into_iter() {
+        spawn_job(options, config, knowledge_base, context, tasks, entry);
+    }
+    Ok(())
+}
+
+/// The reason to refuse to answer `email`, if there is one:  it is one of our own replies or it has
+/// been answered too many times already.
+fn refuse(config: &Config, email: &[u8]) -> Option<String> {
+    let headers = mime::parse_headers(email);
+    let looped = headers.iter().any(|header| match header {
+        Header::Other(name, value) if name.eq_ignore_ascii_case(X_LOOP) => {
+            value.trim().eq_ignore_ascii_case(LOOP_TOKEN)
+        }
+        _ => false,
+    });
+    if looped {
+        return Some(format!(
+            "it carries {}: {} and so is a reply maildir-ai wrote",
+            X_LOOP, LOOP_TOKEN
+        ));
+    }
+    let hops = hops(&headers);
+    if hops > config.max_hops {
+        return Some(format!(
+            "it is {} replies from its prompt, more than the limit of {}",
+            hops, config.max_hops
+        ));
+    }
+    None
+}
+
+/// Move `path` to the Quarantine folder and explain why in a report beside it.
+fn quarantine(
+    knowledge_base: &Path<'_>,
+    path: &Path<'_>,
+    email: &[u8],
+    reason: &str,
+) -> Result<(), std::io::Error> {
+    for level2 in &[CUR, NEW, TMP] {
+        std::fs::create_dir_all(knowledge_base.join(QUARANTINE).join(*level2))?;
+    }
+    let headers = mime::parse_headers(email);
+    let subject = headers
+        .iter()
+        .find_map(|header| match header {
+            Header::Subject(x) => Some(x.clone()),
+            _ => None,
+        })
+        .unwrap_or_default();
+    let mut report = notice_headers(format!("Quarantined: {}", subject));
+    if let Some(msg_id) = headers.iter().find_map(|header| match header {
+        Header::MessageID(x) => Some(x.clone()),
+        _ => None,
+    }) {
+        report.push(Header::InReplyTo(msg_id.clone()));
+        report.push(Header::References(msg_id));
+    }
+    report.push(Header::Other(
+        X_AI_QUARANTINED.to_string(),
+        path.basename().to_string(),
+    ));
+    report.push(Header::MimeVersion("1.0".to_string()));
+    report.push(Header::ContentType(
+        ParameterizedValue::new("text/plain").with_param("charset", "utf-8"),
+    ));
+    report.push(Header::ContentTransferEncoding("8bit".to_string()));
+    let quarantined = knowledge_base
+        .join(QUARANTINE)
+        .join(CUR)
+        .join(path.basename());
+    let report = format!(
+        "{}\n\nmaildir-ai did not answer {} because {}.\n\nThe message is in {} beside this report.  \
+         Once it is fixed, send it back with:\n\n    maildir-ai requeue {}\n",
+        Header::to_block(&report),
+        path.basename(),
+        reason,
+        QUARANTINE,
+        quarantined,
+    );
+    std::fs::rename(path, &quarantined)?;
+    save_to(knowledge_base, QUARANTINE, report)?;
+    Ok(())
+}
+
+////////////////////////////////////////////// requeue /////////////////////////////////////////////
+
+/// Send a quarantined message back to Sent so that maintain tries it again, returning where it went.
+///
+/// `path` may be the message or the report quarantine wrote beside it.  Given the report, the
+/// message it names is requeued and the report moves to Trash.
+pub fn requeue(
+    knowledge_base: &Path<'_>,
+    path: &Path<'_>,
+) -> Result<Path<'static>, std::io::Error> {
+    let email = std::fs::read(path)?;
+    let quarantined = mime::parse_headers(&email)
+        .into_iter()
+        .find_map(|header| match header {
+            Header::Other(name, value) if name.eq_ignore_ascii_case(X_AI_QUARANTINED) => {
+                Some(value.trim().to_string())
+            }
+            _ => None,
+        });
+    let message = match quarantined {
+        Some(name) => {
+            let Some(message) = maildir::find(&knowledge_base.join(QUARANTINE), &name) else {
                 return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!("missing To header in email: {}", path),
+                    std::io::ErrorKind::NotFound,
+                    format!("{} is no longer in {}", name, QUARANTINE),
                 ));
             };
-            let to = to
-                .split(",")
-                .map(|x| x.trim().to_string())
-                .collect::<Vec<_>>();
-            for to in to.into_iter() {
-                let options = options.clone();
-                let knowledge_base = knowledge_base.clone().into_owned();
-                let path = path.clone();
-                let email = email.clone();
-                tokio::task::spawn(async move {
-                    let email = match process_one(&options, &path, &to, &email).await {
-                        Ok(email) => email,
-                        Err(e) => match format_reply(&to, email.clone()) {
-                            Ok(mut email) => {
-                                email.push_str("\n\n");
-                                email += &format!("error processing: {}", e);
-                                email
-                            }
-                            Err(e) => {
-                                format!("error processing: {}\n", e)
-                            }
-                        },
-                    };
-                    let now = SystemTime::now()
-                        .duration_since(SystemTime::UNIX_EPOCH)
-                        .expect("time should go forwards only");
-                    let save_as = format!(
-                        "{:0.5}_1.{}:2,",
-                        now.as_secs_f64(),
-                        std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_string())
-                    );
-                    eprintln!("saving.... to {}", save_as);
-                    let _ =
-                        std::fs::write(knowledge_base.join(INBOX).join(CUR).join(&save_as), email);
-                });
-            }
             std::fs::rename(
-                &path,
-                knowledge_base.join(INBOX).join(CUR).join(path.basename()),
+                path,
+                knowledge_base.join(TRASH).join(CUR).join(path.basename()),
             )?;
+            message
+        }
+        None => path.clone().into_owned(),
+    };
+    let sent = knowledge_base
+        .join(SENT)
+        .join(CUR)
+        .join(message.basename())
+        .into_owned();
+    std::fs::rename(&message, &sent)?;
+    Ok(sent)
+}
+
+/////////////////////////////////////////////// queue //////////////////////////////////////////////
+
+/// The jobs of a knowledge base that are not done or cancelled, in the order they stand:  running jobs, then
+/// pending ones in the order they will get their turn, then held and recently failed ones.
+pub fn queue(knowledge_base: &Path<'_>) -> Result<Vec<Entry>, std::io::Error> {
+    let mut entries = Journal::open(knowledge_base)?.entries()?;
+    let now = chrono::Utc::now();
+    entries.retain(|entry| {
+        !matches!(entry.state, State::Done | State::Cancelled) && !entry.expired(now)
+    });
+    // The journal lists entries oldest first and the sort is stable, so ties stay first come first.
+    entries.sort_by_key(|entry| {
+        let rank = match entry.state {
+            State::Running => 0,
+            State::Pending => 1,
+            State::Held => 2,
+            State::Failed => 3,
+            State::Done | State::Cancelled => 4,
+        };
+        (rank, entry.job.priority)
+    });
+    Ok(entries)
+}
+
+/// The headers that start a message maildir-ai writes on its own behalf.
+fn notice_headers(subject: String) -> Vec<Header> {
+    vec![
+        Header::From("maildir-ai <maildir-ai@localhost>".to_string()),
+        Header::Subject(subject),
+        Header::Date(chrono::Utc::now().to_rfc2822()),
+        Header::MessageID(generate_message_id()),
+    ]
+}
+
+/// Answer `email` as `to`, deliver the reply (or a bounce) to INBOX, and return where the reply was
+/// delivered and the answer.
+///
+/// Failures that may pass are retried according to the model's retry policy.  If they persist, no
+/// reply is delivered and the error is returned for the caller to hold the job.  `permit` is the
+/// turn to make the request; it is given up while backing off between attempts, and None is
+/// returned if no new turn comes because the scheduler closed.
+async fn reply(
+    options: &MaintainOptions,
+    config: &Config,
+    knowledge_base: &Path<'_>,
+    path: &Path<'_>,
+    to: &str,
+    email: &[u8],
+    mut permit: Permit,
+) -> Option<(Option<Path<'static>>, Result<String, std::io::Error>)> {
+    let persona = config.persona(to);
+    let backend = options.yammer.url();
+    let bounce = |attempts, error: &std::io::Error| {
+        let failure = Failure {
+            recipient: to,
+            model: persona.model(),
+            backend: &backend,
+            attempts,
+            error,
+        };
+        format_bounce(&failure, email)
+    };
+    // A message whose headers cannot be replied to is bounced without bothering the model.
+    let mut reply = match format_reply(to, email) {
+        Ok(reply) => reply,
+        Err(err) => return Some((save_to_inbox(knowledge_base, bounce(0, &err)), Err(err))),
+    };
+    let retry = config.retry(to);
+    let mut attempt = 1;
+    let answer = loop {
+        match process_one(options, config, knowledge_base, path, to, email).await {
+            Err(err) if is_transient(&err) && attempt < retry.attempts => {
+                let backoff = retry.backoff(attempt);
+                eprintln!(
+                    "retrying: {} to {} in {}s after attempt {} failed: {}",
+                    path,
+                    to,
+                    backoff.as_secs(),
+                    attempt,
+                    err
+                );
+                // Let other prompts to the model go while this one waits.
+                permit = permit.pause(backoff).await?;
+                attempt += 1;
+            }
+            answer => break answer,
+        }
+    };
+    drop(permit);
+    Some(match answer {
+        Ok(answer) => {
+            reply.push_str("\n\n");
+            reply += &answer;
+            (save_to_inbox(knowledge_base, reply), Ok(answer))
+        }
+        Err(err) if is_transient(&err) => (None, Err(err)),
+        Err(err) => (
+            save_to_inbox(knowledge_base, bounce(attempt, &err)),
+            Err(err),
+        ),
+    })
+}
+
+/// Save a message to INBOX.
+fn save_to_inbox(knowledge_base: &Path<'_>, email: String) -> Option<Path<'static>> {
+    match save_to(knowledge_base, INBOX, email) {
+        Ok(path) => Some(path),
+        Err(err) => {
+            eprintln!("error: could not deliver to {}: {}", INBOX, err);
+            None
         }
     }
-    Ok(())
 }
 
+/// Deliver a message to `folder`.
+fn save_to(
+    knowledge_base: &Path<'_>,
+    folder: &str,
+    email: String,
+) -> Result<Path<'static>, std::io::Error> {
+    let path = maildir::deliver(&knowledge_base.join(folder), email)?;
+    eprintln!("saving.... to {}", path);
+    Ok(path)
+}
+
+/// The conversation the model behind `to` is given to answer `email`:  the thread so far, oldest
+/// first, then `email` itself.  The persona's system prompt, if any, goes ahead of these.
+///
+/// The context budget is shared by the whole conversation and spent newest first, so the
+/// attachments of earlier messages are the first to be cut.
+pub fn prompt_messages(
+    config: &Config,
+    knowledge_base: &Path<'_>,
+    to: &str,
+    email: &[u8],
+) -> Result<Vec<ChatMessage>, std::io::Error> {
+    let local_part = Mailbox::new(to).local_part().to_string();
+    let thread = Thread::reconstruct(knowledge_base, email)?;
+    let mut budget = config.context_budget;
+    let mut messages = vec![chat_message("user", email, &mut budget)];
+    for ancestor in thread.ancestors().iter().rev() {
+        let role = if sent_by(ancestor, to) || sent_by(ancestor, &local_part) {
+            "assistant"
+        } else {
+            "user"
+        };
+        messages.push(chat_message(role, ancestor, &mut budget));
+    }
+    messages.reverse();
+    Ok(messages)
+}
+
+/// Ask the model behind `to` to answer `email`, returning the answer wrapped for mail.
 async fn process_one(
     options: &MaintainOptions,
+    config: &Config,
+    knowledge_base: &Path<'_>,
     path: &Path<'_>,
     to: &str,
-    email: &String,
+    email: &[u8],
 ) -> Result<String, std::io::Error> {
-    // SAFETY(rescrv):  It will always return at least one string.
-    let to = to.split("@").next().unwrap().to_string();
-    eprintln!("processing: {} to {}", path, to);
-    let generate = GenerateRequest {
-        model: to.clone(),
-        prompt: email.clone(),
-        suffix: "".to_string(),
-        system: None,
-        stream: None,
-        template: None,
-        raw: None,
-        format: None,
-        images: None,
-        keep_alive: None,
+    let persona = config.persona(to);
+    eprintln!("processing: {} to {}", path, persona.model());
+    // Rebuilding the thread reads the knowledge base, so keep it off the executor.
+    let messages = {
+        let config = config.clone();
+        let knowledge_base = knowledge_base.clone().into_owned();
+        let to = to.to_string();
+        let email = email.to_vec();
+        tokio::task::spawn_blocking(move || prompt_messages(&config, &knowledge_base, &to, &email))
+            .await
+            .map_err(std::io::Error::other)??
     };
-    let mut buf = vec![];
-    Request::generate(options.yammer.clone(), generate)?
-        .accumulate(&mut FieldWriteAccumulator::new(&mut buf, "response"))
+    let images = messages
+        .iter()
+        .map(|m| m.images.as_ref().map(Vec::len).unwrap_or(0))
+        .sum::<usize>();
+    let mut acc = ResponseAccumulator::default();
+    build_request(options, &persona, messages)?
+        .accumulate(&mut acc)
         .await
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", e)))?;
-    let buf = String::from_utf8(buf)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", e)))?;
-    let mut email = format_reply(&to, email)?;
-    email.push_str("\n\n");
+        .map_err(|e| {
+            let e = backend_error(e);
+            if is_transient(&e) {
+                return e;
+            }
+            let lower = e.to_string().to_ascii_lowercase();
+            if images > 0
+                && ["image", "vision", "multimodal"]
+                    .iter()
+                    .any(|x| lower.contains(x))
+            {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "{} rejected the {} image attachment(s) in this thread; send images to a \
+                         multimodal model such as llava: {}",
+                        persona.model(),
+                        images,
+                        e
+                    ),
+                )
+            } else {
+                e
+            }
+        })?;
+    let buf = acc.response;
     fn wrap_line(line: &str) -> String {
         let mut offset = 0usize;
         let mut wrapped = String::new();
         let indent = if line.trim_start().starts_with("* ") {
-            line.chars().count() - line.trim_start().chars().count() - 2
+            (line.chars().count() - line.trim_start().chars().count()).saturating_sub(2)
         } else {
             line.chars().count() - line.trim_start().chars().count()
         };
@@ -235,41 +1307,224 @@ async fn process_one(
         }
         wrapped
     }
-    email += &wrap_answer(&buf);
-    Ok(email)
+    Ok(wrap_answer(&buf))
 }
 
-////////////////////////////////////////////// Header //////////////////////////////////////////////
+/// Convert an error from the backend to an I/O error whose kind says whether it may pass:  see
+/// [is_transient].
+fn backend_error(err: yammer::Error) -> std::io::Error {
+    use std::io::ErrorKind;
+    let kind = match &err {
+        yammer::Error::Request(err) if err.is_timeout() => ErrorKind::TimedOut,
+        yammer::Error::Request(err) if err.is_connect() => ErrorKind::ConnectionRefused,
+        yammer::Error::Request(err) if err.is_builder() => ErrorKind::InvalidInput,
+        // Anything else went wrong on the wire, e.g. ollama restarting mid-stream.
+        yammer::Error::Request(_) => ErrorKind::ConnectionReset,
+        yammer::Error::Io(err) => err.kind(),
+        yammer::Error::Message(msg) => {
+            let msg = msg.to_ascii_lowercase();
+            if [
+                "busy",
+                "try again",
+                "overloaded",
+                "unavailable",
+                "timed out",
+                "timeout",
+                "terminated",
+                "connection",
+            ]
+            .iter()
+            .any(|x| msg.contains(x))
+            {
+                ErrorKind::ResourceBusy
+            } else {
+                ErrorKind::InvalidData
+            }
+        }
+        yammer::Error::Json(_) => ErrorKind::InvalidData,
+    };
+    std::io::Error::new(kind, format!("{:?}", err))
+}
 
-/// A Header likely to be produced by Mutt.
-#[derive(Clone, Debug)]
-pub enum Header {
-    From(String),
-    To(String),
-    Cc(String),
-    Subject(String),
-    Date(String),
-    MessageID(String),
-    MimeVersion,
-    ContentType,
+/// True if `err` may pass on its own, such as the backend being unreachable or busy, so the request
+/// is worth retrying.  Everything else, such as a model that does not exist, is permanent.
+pub(crate) fn is_transient(err: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        err.kind(),
+        ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::BrokenPipe
+            | ErrorKind::TimedOut
+            | ErrorKind::ResourceBusy
+            | ErrorKind::Interrupted
+            | ErrorKind::UnexpectedEof
+    )
+}
+
+/// Build the request for the persona.  Personas with a template go to the generate endpoint with the
+/// thread rendered as a transcript; all others go to the chat endpoint.
+fn build_request(
+    options: &MaintainOptions,
+    persona: &Persona,
+    mut messages: Vec<ChatMessage>,
+) -> Result<Request, std::io::Error> {
+    let (api, mut payload) = if let Some(template) = &persona.template {
+        let prompt = messages
+            .iter()
+            .map(|m| format!("{}: {}", m.role, m.content.trim()))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let images = messages
+            .iter()
+            .flat_map(|m| m.images.iter().flatten().cloned())
+            .collect::<Vec<_>>();
+        let generate = GenerateRequest {
+            model: persona.model().to_string(),
+            prompt,
+            suffix: "".to_string(),
+            system: persona.system.clone(),
+            stream: None,
+            template: Some(template.clone()),
+            raw: None,
+            format: None,
+            images: if images.is_empty() {
+                None
+            } else {
+                Some(images)
+            },
+            keep_alive: None,
+        };
+        ("generate", serde_json::to_value(generate)?)
+    } else {
+        if let Some(system) = &persona.system {
+            messages.insert(
+                0,
+                ChatMessage {
+                    role: "system".to_string(),
+                    content: system.clone(),
+                    images: None,
+                    tool_calls: None,
+                },
+            );
+        }
+        let chat = ChatRequest {
+            model: persona.model().to_string(),
+            messages,
+            tools: None,
+            format: None,
+            stream: None,
+            keep_alive: None,
+        };
+        ("chat", serde_json::to_value(chat)?)
+    };
+    if let (Some(opts), Some(payload)) = (&persona.options, payload.as_object_mut()) {
+        payload.insert(
+            "options".to_string(),
+            serde_json::Value::Object(opts.clone()),
+        );
+    }
+    Ok(Request {
+        url: options.yammer.url(),
+        api: api.to_string(),
+        payload: serde_json::to_string(&payload)?,
+        streaming: true,
+    })
+}
+
+fn chat_message(role: &str, message: &[u8], budget: &mut usize) -> ChatMessage {
+    let images = extract_images(message);
+    ChatMessage {
+        role: role.to_string(),
+        content: extract_prompt(message, budget),
+        images: if images.is_empty() {
+            None
+        } else {
+            Some(images)
+        },
+        tool_calls: None,
+    }
+}
+
+fn sent_by(message: &[u8], from: &str) -> bool {
+    mime::parse_headers(message)
+        .iter()
+        .any(|header| match header {
+            Header::From(x) => x
+                .parse::<AddressList>()
+                .map(|x| x.contains(from))
+                .unwrap_or(false),
+            _ => false,
+        })
+}
+
+//////////////////////////////////////// ResponseAccumulator ///////////////////////////////////////
+
+/// Accumulate the text of a streamed chat or generate response.
+#[derive(Debug, Default)]
+struct ResponseAccumulator {
+    response: String,
+}
+
+impl Accumulator for ResponseAccumulator {
+    fn accumulate(&mut self, message: serde_json::Value) -> std::ops::ControlFlow<()> {
+        if let Some(serde_json::Value::String(content)) = message
+            .get("message")
+            .and_then(|message| message.get("content"))
+        {
+            self.response.push_str(content);
+        } else if let Some(serde_json::Value::String(response)) = message.get("response") {
+            self.response.push_str(response);
+        }
+        std::ops::ControlFlow::Continue(())
+    }
+}
+
+////////////////////////////////////////////// Header //////////////////////////////////////////////
+
+/// A header of an RFC 5322 message.
+///
+/// Field names are matched case-insensitively.  Headers this crate does not interpret are kept as
+/// [Header::Other], so parsing a header block and writing it back out with [Header::to_block] keeps
+/// every field, though not always as it was written:  folded lines are joined, well-known field
+/// names are written in their canonical case, and structured values are written canonically,
+/// without comments or parameters that lack a value.  RFC 2047 encoded words are decoded on the way
+/// in and re-encoded on the way out.  What [Header::to_block] writes parses back to the same headers.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Header {
+    From(String),
+    To(String),
+    Cc(String),
+    ReplyTo(String),
+    Subject(String),
+    Date(String),
+    MessageID(String),
+    MimeVersion(String),
+    ContentType(ParameterizedValue),
+    ContentTransferEncoding(String),
     References(String),
-    ContentDisposition,
+    ContentDisposition(ParameterizedValue),
     InReplyTo(String),
+    Other(String, String),
 }
 
 impl Header {
-    fn from_block(header_block: impl AsRef<str>) -> Result<Vec<Self>, std::io::Error> {
+    /// Parse a block of headers, unfolding continuation lines.  Lines that are not headers are
+    /// skipped.
+    pub fn from_block(header_block: impl AsRef<str>) -> Result<Vec<Self>, std::io::Error> {
         let mut headers = vec![];
         let mut current_line = "".to_string();
         for line in header_block.as_ref().lines() {
+            let line = line.strip_suffix('\r').unwrap_or(line);
             if line.starts_with(' ') || line.starts_with('\t') {
                 current_line.push_str(line);
             } else {
                 if !current_line.is_empty() {
-                    let Ok(header) = current_line.parse::<Header>() else {
-                        continue;
-                    };
-                    headers.push(header);
+                    if let Ok(header) = current_line.parse::<Header>() {
+                        headers.push(header);
+                    }
                 }
                 current_line = line.to_string();
             }
@@ -282,21 +1537,61 @@ impl Header {
         Ok(headers)
     }
 
+    /// Write a block of headers, one per line.
+    pub fn to_block(headers: &[Self]) -> String {
+        headers
+            .iter()
+            .map(|header| header.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// The name of this header's field.
+    pub fn name(&self) -> &str {
+        match self {
+            Header::From(_) => "From",
+            Header::To(_) => "To",
+            Header::Cc(_) => "Cc",
+            Header::ReplyTo(_) => "Reply-To",
+            Header::Subject(_) => "Subject",
+            Header::Date(_) => "Date",
+            Header::MessageID(_) => "Message-ID",
+            Header::MimeVersion(_) => "MIME-Version",
+            Header::ContentType(_) => "Content-Type",
+            Header::ContentTransferEncoding(_) => "Content-Transfer-Encoding",
+            Header::References(_) => "References",
+            Header::ContentDisposition(_) => "Content-Disposition",
+            Header::InReplyTo(_) => "In-Reply-To",
+            Header::Other(name, _) => name,
+        }
+    }
+
+    /// The value of this header's field.
+    pub fn value(&self) -> String {
+        match self {
+            Header::From(x)
+            | Header::To(x)
+            | Header::Cc(x)
+            | Header::ReplyTo(x)
+            | Header::Subject(x)
+            | Header::Date(x)
+            | Header::MessageID(x)
+            | Header::MimeVersion(x)
+            | Header::ContentTransferEncoding(x)
+            | Header::References(x)
+            | Header::InReplyTo(x)
+            | Header::Other(_, x) => x.clone(),
+            Header::ContentType(x) | Header::ContentDisposition(x) => x.to_string(),
+        }
+    }
+
+    /// True if this header is named `name`, ignoring case.
+    pub fn is(&self, name: &str) -> bool {
+        self.name().eq_ignore_ascii_case(name)
+    }
+
     fn of_same_type(a: &Self, b: &Self) -> bool {
-        matches!(
-            (a, b),
-            (Header::From(_), Header::From(_))
-                | (Header::To(_), Header::To(_))
-                | (Header::Cc(_), Header::Cc(_))
-                | (Header::Subject(_), Header::Subject(_))
-                | (Header::Date(_), Header::Date(_))
-                | (Header::MessageID(_), Header::MessageID(_))
-                | (Header::MimeVersion, Header::MimeVersion)
-                | (Header::ContentType, Header::ContentType)
-                | (Header::References(_), Header::References(_))
-                | (Header::ContentDisposition, Header::ContentDisposition)
-                | (Header::InReplyTo(_), Header::InReplyTo(_))
-        )
+        a.name().eq_ignore_ascii_case(b.name())
     }
 }
 
@@ -304,68 +1599,335 @@ impl FromStr for Header {
     type Err = std::io::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if let Some(from) = s.strip_prefix("From: ") {
-            Ok(Header::From(from.to_string()))
-        } else if let Some(to) = s.strip_prefix("To: ") {
-            Ok(Header::To(to.to_string()))
-        } else if let Some(cc) = s.strip_prefix("Cc: ") {
-            Ok(Header::Cc(cc.to_string()))
-        } else if let Some(subject) = s.strip_prefix("Subject: ") {
-            Ok(Header::Subject(subject.to_string()))
-        } else if let Some(date) = s.strip_prefix("Date: ") {
-            Ok(Header::Date(date.to_string()))
-        } else if let Some(msg_id) = s.strip_prefix("Message-ID: ") {
-            Ok(Header::MessageID(msg_id.to_string()))
-        } else if s == "MIME-Version: 1.0" {
-            Ok(Header::MimeVersion)
-        } else if s == "Content-Type: text/plain; charset=us-ascii" {
-            Ok(Header::ContentType)
-        } else if let Some(refs) = s.strip_prefix("References: ") {
-            Ok(Header::References(refs.to_string()))
-        } else if s == "Content-Disposition: inline" {
-            Ok(Header::ContentDisposition)
-        } else if let Some(in_reply_to) = s.strip_prefix("In-Reply-To: ") {
-            Ok(Header::InReplyTo(in_reply_to.to_string()))
-        } else {
-            Err(std::io::Error::new(
+        let invalid = || {
+            std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 format!("invalid header: {}", s),
-            ))
+            )
+        };
+        let Some((name, value)) = s.split_once(':') else {
+            return Err(invalid());
+        };
+        if name.is_empty() || !name.bytes().all(|b| (33..=126).contains(&b)) {
+            return Err(invalid());
         }
+        let value = value.trim_start_matches([' ', '\t']).to_string();
+        Ok(match name.to_ascii_lowercase().as_str() {
+            "from" => Header::From(rfc2047::decode_structured(&value)),
+            "to" => Header::To(rfc2047::decode_structured(&value)),
+            "cc" => Header::Cc(rfc2047::decode_structured(&value)),
+            "reply-to" => Header::ReplyTo(rfc2047::decode_structured(&value)),
+            "subject" => Header::Subject(rfc2047::decode(&value)),
+            "date" => Header::Date(value),
+            "message-id" => Header::MessageID(value),
+            "mime-version" => Header::MimeVersion(value),
+            "content-type" => Header::ContentType(value.parse()?),
+            "content-transfer-encoding" => Header::ContentTransferEncoding(value),
+            "references" => Header::References(value),
+            "content-disposition" => Header::ContentDisposition(value.parse()?),
+            "in-reply-to" => Header::InReplyTo(value),
+            _ => Header::Other(name.to_string(), value),
+        })
     }
 }
 
+impl std::fmt::Display for Header {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Values are kept decoded; non-ASCII text goes back out as RFC 2047 encoded words.
+        match self {
+            Header::From(x) | Header::To(x) | Header::Cc(x) | Header::ReplyTo(x) => {
+                match x.parse::<AddressList>() {
+                    Ok(list) => write!(f, "{}: {}", self.name(), wrap_header(list.encoded())),
+                    Err(_) => write!(f, "{}: {}", self.name(), x),
+                }
+            }
+            // Fold between encoded words so that a long non-ASCII subject stays within line limits.
+            Header::Subject(x) => {
+                write!(f, "{}: {}", self.name(), wrap_header(rfc2047::encode(x)))
+            }
+            Header::References(x) | Header::InReplyTo(x) => {
+                write!(f, "{}: {}", self.name(), wrap_header(x))
+            }
+            _ => write!(f, "{}: {}", self.name(), self.value()),
+        }
+    }
+}
+
+//////////////////////////////////////// ParameterizedValue ////////////////////////////////////////
+
+/// A structured header value with parameters, like `text/plain; charset=us-ascii`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ParameterizedValue {
+    pub value: String,
+    pub params: Vec<(String, String)>,
+}
+
+impl ParameterizedValue {
+    /// Create a new value without parameters.
+    pub fn new(value: impl Into<String>) -> Self {
+        Self {
+            value: value.into(),
+            params: vec![],
+        }
+    }
+
+    /// Add a parameter to the value.
+    pub fn with_param(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.params.push((name.into(), value.into()));
+        self
+    }
+
+    /// The value of the parameter `name`, ignoring case.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// True if the value is `value`, ignoring case.
+    pub fn is(&self, value: &str) -> bool {
+        self.value.eq_ignore_ascii_case(value)
+    }
+}
+
+impl FromStr for ParameterizedValue {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Split on semicolons outside of quoted strings, dropping comments as we go.
+        let mut pieces = vec![String::new()];
+        let mut quoted = false;
+        let mut escaped = false;
+        let mut comment = 0usize;
+        for c in s.chars() {
+            // SAFETY(rescrv):  pieces starts non-empty and only grows.
+            let piece = pieces.last_mut().unwrap();
+            if escaped {
+                piece.push(c);
+                escaped = false;
+            } else if quoted {
+                match c {
+                    '\\' => escaped = true,
+                    '"' => quoted = false,
+                    _ => piece.push(c),
+                }
+            } else if comment > 0 {
+                match c {
+                    '(' => comment += 1,
+                    ')' => comment -= 1,
+                    _ => {}
+                }
+            } else {
+                match c {
+                    '"' => quoted = true,
+                    '(' => comment += 1,
+                    ';' => pieces.push(String::new()),
+                    _ => piece.push(c),
+                }
+            }
+        }
+        let mut pieces = pieces.into_iter();
+        let value = pieces.next().unwrap_or_default().trim().to_string();
+        let mut params = vec![];
+        for piece in pieces {
+            if piece.trim().is_empty() {
+                continue;
+            }
+            // A parameter without a value says nothing, and failing on it would lose the whole header.
+            let Some((name, value)) = piece.split_once('=') else {
+                continue;
+            };
+            params.push((name.trim().to_string(), value.trim().to_string()));
+        }
+        Ok(Self { value, params })
+    }
+}
+
+impl std::fmt::Display for ParameterizedValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.value)?;
+        for (name, value) in self.params.iter() {
+            let needs_quotes = value.is_empty()
+                || value.chars().any(|c| {
+                    c.is_ascii_whitespace()
+                        || c.is_ascii_control()
+                        || "()<>@,;:\\\"/[]?=".contains(c)
+                });
+            if needs_quotes {
+                let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+                write!(f, "; {}=\"{}\"", name, value)?;
+            } else {
+                write!(f, "; {}={}", name, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+////////////////////////////////////////// extract_prompt //////////////////////////////////////////
+
+/// Extract the text of a message that will be shown to the model.
+///
+/// Text attachments are appended as labeled blocks, each spending its length from `budget` until
+/// none is left.  Anything cut short or left out is noted at the end of the prompt.
+pub fn extract_prompt(message: impl AsRef<[u8]>, budget: &mut usize) -> String {
+    let message = Message::parse(message);
+    let mut prompt = clean_prompt(&message.text);
+    let remaining = budget;
+    let mut notes = vec![];
+    for attachment in message.attachments.iter() {
+        if attachment.content_type.starts_with("image/") {
+            continue;
+        }
+        if !attachment.is_text() {
+            notes.push(format!(
+                "attachment {} is not text and was left out",
+                attachment.label()
+            ));
+            continue;
+        }
+        let text = String::from_utf8_lossy(&attachment.body).replace("\r\n", "\n");
+        if *remaining == 0 {
+            notes.push(format!(
+                "attachment {} was left out to fit the context budget",
+                attachment.label()
+            ));
+            continue;
+        }
+        let mut included = text.as_str();
+        if included.len() > *remaining {
+            let mut end = *remaining;
+            while !included.is_char_boundary(end) {
+                end -= 1;
+            }
+            end = included[..end].rfind('\n').map(|x| x + 1).unwrap_or(end);
+            included = &included[..end];
+            notes.push(format!(
+                "attachment {} was truncated to {} of {} bytes to fit the context budget",
+                attachment.label(),
+                included.len(),
+                text.len()
+            ));
+        }
+        *remaining -= included.len();
+        if !prompt.is_empty() {
+            prompt.push('\n');
+        }
+        prompt += &format!("--- attachment: {} ---\n", attachment.label());
+        prompt += included;
+        if !included.is_empty() && !included.ends_with('\n') {
+            prompt.push('\n');
+        }
+        prompt += "--- end of attachment ---\n";
+    }
+    if !notes.is_empty() {
+        prompt.push('\n');
+        for note in notes {
+            prompt += &format!("[note: {}]\n", note);
+        }
+    }
+    prompt
+}
+
+////////////////////////////////////////// extract_images //////////////////////////////////////////
+
+/// Extract the image attachments of a message, base64-encoded for a multimodal model.
+pub fn extract_images(message: impl AsRef<[u8]>) -> Vec<String> {
+    use base64::Engine;
+    Message::parse(message)
+        .attachments
+        .iter()
+        .filter(|attachment| attachment.content_type.starts_with("image/"))
+        .map(|attachment| base64::engine::general_purpose::STANDARD.encode(&attachment.body))
+        .collect()
+}
+
 //////////////////////////////////////////// extract_to ////////////////////////////////////////////
 
 /// Extract the To header from a message.
-pub fn extract_to(message: impl AsRef<str>) -> Option<String> {
-    let message = message.as_ref();
-    let (header_block, _) = message.split_once("\n\n").unwrap_or(("", ""));
-    let headers = match Header::from_block(header_block) {
-        Ok(x) => x,
-        Err(err) => {
-            eprintln!("error: {:?}", err);
-            return None;
+pub fn extract_to(message: impl AsRef<[u8]>) -> Option<AddressList> {
+    let mut to = None::<AddressList>;
+    for header in mime::parse_headers(message.as_ref()) {
+        if let Header::To(x) = header {
+            match x.parse::<AddressList>() {
+                Ok(x) => to.get_or_insert_with(AddressList::default).extend(x),
+                Err(err) => eprintln!("error: {:?}", err),
+            }
         }
-    };
-    if let Some(Header::To(to)) = headers
-        .iter()
-        .find(|header| matches!(header, Header::To(_)))
-    {
-        Some(to.clone())
-    } else {
-        None
     }
+    to
+}
+
+//////////////////////////////////////// extract_recipients ////////////////////////////////////////
+
+/// Extract everyone a message should be dispatched to:  the To, Cc, and X-AI-Bcc recipients, less
+/// the sender and duplicates.
+pub fn extract_recipients(message: impl AsRef<[u8]>) -> AddressList {
+    let headers = mime::parse_headers(message.as_ref());
+    let mut recipients = AddressList::default();
+    let mut senders = AddressList::default();
+    for header in headers.iter() {
+        let (list, value) = match header {
+            Header::To(x) | Header::Cc(x) => (&mut recipients, x),
+            Header::Other(name, x) if name.eq_ignore_ascii_case(X_AI_BCC) => (&mut recipients, x),
+            Header::From(x) => (&mut senders, x),
+            _ => continue,
+        };
+        match value.parse::<AddressList>() {
+            Ok(x) => list.extend(x),
+            Err(err) => eprintln!("error: {:?}", err),
+        }
+    }
+    recipients.retain(|mailbox| !senders.contains(&mailbox.address));
+    recipients.dedup();
+    recipients
+}
+
+/////////////////////////////////////////// debate_rounds //////////////////////////////////////////
+
+/// The number of rounds of debate a message asks for, if it asks for a debate.
+pub fn debate_rounds(message: impl AsRef<[u8]>) -> Option<usize> {
+    mime::parse_headers(message.as_ref())
+        .iter()
+        .find_map(|header| match header {
+            Header::Other(name, value) if name.eq_ignore_ascii_case(X_AI_DEBATE) => {
+                value.trim().parse::<usize>().ok()
+            }
+            _ => None,
+        })
+        .filter(|rounds| *rounds > 0)
+}
+
+/// The priority of `message` from its X-Priority header, from 1 (highest) to 5 (lowest).
+///
+/// X-Priority is written as a digit, optionally followed by a comment such as "1 (Highest)".
+pub fn priority(message: impl AsRef<[u8]>) -> u8 {
+    mime::parse_headers(message.as_ref())
+        .iter()
+        .find_map(|header| match header {
+            Header::Other(name, value) if name.eq_ignore_ascii_case(X_PRIORITY) => value
+                .trim()
+                .chars()
+                .next()
+                .and_then(|c| c.to_digit(10))
+                .filter(|p| (1..=5).contains(p))
+                .map(|p| p as u8),
+            _ => None,
+        })
+        .unwrap_or(NORMAL_PRIORITY)
 }
 
 /////////////////////////////////////////// format_reply ///////////////////////////////////////////
 
 /// Format a reply to an email as if the reply comes from "From".
-pub fn format_reply(from: &str, message: impl AsRef<str>) -> Result<String, std::io::Error> {
-    let message = message.as_ref();
-    let (header_block, body) = message.split_once("\n\n").unwrap_or(("", ""));
-    let mut headers = Header::from_block(header_block)?;
+pub fn format_reply(from: &str, message: impl AsRef<[u8]>) -> Result<String, std::io::Error> {
+    let message = Message::parse(message);
+    let body = message.text.trim_end_matches('\n');
+    let mut headers = message.headers.clone();
     let orig_headers = headers.clone();
+    // drop the headers that describe the original message rather than the conversation
+    headers.retain(|header| !matches!(header, Header::Other(_, _) | Header::ReplyTo(_)));
     // rewrite the date header
     let date = chrono::Utc::now().to_rfc2822();
     let mut orig_date = None;
@@ -383,34 +1945,7 @@ pub fn format_reply(from: &str, message: impl AsRef<str>) -> Result<String, std:
             "missing Date header",
         ));
     };
-    // turn every to into a cc
-    for header in headers.iter_mut() {
-        if let Header::To(x) = header {
-            *header = Header::Cc(x.clone());
-        }
-    }
-    // coalesce the cc headers
-    let mut cc = None;
-    headers.retain(|header| {
-        if let Header::Cc(x) = header {
-            if let Some(s) = &cc {
-                cc = Some(format!("{}, {}", s, x));
-            } else {
-                cc = Some(x.clone());
-            }
-            false
-        } else {
-            true
-        }
-    });
-    if let Some(cc) = cc {
-        let mut pieces = cc.split(", ").collect::<Vec<_>>();
-        pieces.retain(|x| *x != from);
-        if !pieces.is_empty() {
-            headers.push(Header::Cc(wrap_header(pieces.join(","))));
-        }
-    }
-    // Set the To header to the original sender
+    // Set the To header to the original sender, or where they asked replies to go
     let Some(Header::From(orig_from)) = headers
         .iter()
         .find(|header| matches!(header, Header::From(_)))
@@ -421,21 +1956,56 @@ pub fn format_reply(from: &str, message: impl AsRef<str>) -> Result<String, std:
         ));
     };
     let orig_from = orig_from.clone();
-    headers.push(Header::To(orig_from.clone()));
+    let reply_to = orig_headers
+        .iter()
+        .find_map(|header| match header {
+            Header::ReplyTo(x) => Some(x.clone()),
+            _ => None,
+        })
+        .unwrap_or_else(|| orig_from.clone());
+    let reply_to = reply_to.parse::<AddressList>()?;
+    let from = from.parse::<Mailbox>()?;
+    // turn every to into a cc and coalesce the cc headers, dropping the new sender and recipients
+    let mut cc = AddressList::default();
+    headers.retain(|header| match header {
+        Header::To(x) | Header::Cc(x) => {
+            if let Ok(x) = x.parse::<AddressList>() {
+                cc.extend(x);
+            }
+            false
+        }
+        _ => true,
+    });
+    cc.retain(|mailbox| !mailbox.is(&from.address) && !reply_to.contains(&mailbox.address));
+    cc.dedup();
+    if !cc.addresses.is_empty() {
+        headers.push(Header::Cc(cc.to_string()));
+    }
+    headers.push(Header::To(reply_to.to_string()));
     headers.retain(|header| !matches!(header, Header::From(_)));
     headers.push(Header::From(from.to_string()));
-    // Set the In-Reply-To header to the original message ID
+    // Set In-Reply-To to the original message ID and extend the original's References with it
     headers.retain(|header| !matches!(header, Header::InReplyTo(_)));
     headers.retain(|header| !matches!(header, Header::References(_)));
-    if let Some(Header::MessageID(orig_msg_id)) = orig_headers
-        .iter()
-        .find(|header| matches!(header, Header::MessageID(_)))
-    {
-        headers.push(Header::InReplyTo(orig_msg_id.clone()));
-        headers.push(Header::References(orig_msg_id.clone()));
+    let orig_msg_id = orig_headers.iter().find_map(|header| match header {
+        Header::MessageID(x) => thread::split_msg_ids(x).into_iter().next(),
+        _ => None,
+    });
+    let references = references(&orig_headers, orig_msg_id.as_deref());
+    if let Some(orig_msg_id) = orig_msg_id {
+        headers.push(Header::InReplyTo(orig_msg_id));
+    }
+    if !references.is_empty() {
+        headers.push(Header::References(references.join(" ")));
     }
     headers.retain(|header| !matches!(header, Header::MessageID(_)));
     headers.push(Header::MessageID(generate_message_id()));
+    // Mark the reply as ours and count the hop so that it is never answered in a loop
+    headers.push(Header::Other(X_LOOP.to_string(), LOOP_TOKEN.to_string()));
+    headers.push(Header::Other(
+        X_AI_HOPS.to_string(),
+        (hops(&orig_headers) + 1).to_string(),
+    ));
     // Add Re: to the subject
     if let Some(Header::Subject(subject)) = headers
         .iter_mut()
@@ -447,12 +2017,33 @@ pub fn format_reply(from: &str, message: impl AsRef<str>) -> Result<String, std:
     } else {
         headers.push(Header::Subject("Re: ".to_string()));
     }
-    headers.sort_by(|a, b| {
-        orig_headers
-            .iter()
-            .position(|x| Header::of_same_type(a, x))
-            .cmp(&orig_headers.iter().position(|x| Header::of_same_type(b, x)))
+    // We accept anything, we emit utf-8 plain text
+    headers.retain(|header| {
+        !matches!(
+            header,
+            Header::MimeVersion(_) | Header::ContentType(_) | Header::ContentTransferEncoding(_)
+        )
     });
+    headers.push(Header::MimeVersion("1.0".to_string()));
+    headers.push(Header::ContentType(
+        ParameterizedValue::new("text/plain").with_param("charset", "utf-8"),
+    ));
+    headers.push(Header::ContentTransferEncoding("8bit".to_string()));
+    for header in headers.iter_mut() {
+        if let Header::ContentDisposition(x) = header {
+            *x = ParameterizedValue::new("inline");
+        }
+    }
+    let position = |header: &Header| {
+        let find = |h: &Header| orig_headers.iter().position(|x| Header::of_same_type(h, x));
+        find(header)
+            .or_else(|| match header {
+                Header::Cc(_) => find(&Header::To(String::new())),
+                _ => None,
+            })
+            .unwrap_or(usize::MAX)
+    };
+    headers.sort_by_key(position);
     let body = format!(
         "On {orig_date}, {orig_from} wrote:\n{}",
         body.split("\n")
@@ -460,65 +2051,768 @@ pub fn format_reply(from: &str, message: impl AsRef<str>) -> Result<String, std:
             .collect::<Vec<_>>()
             .join("\n")
     );
-    Ok(format!(
-        "{}\n\n{}",
-        headers
+    Ok(format!("{}\n\n{}", Header::to_block(&headers), body))
+}
+
+///////////////////////////////////////// format_comparison ////////////////////////////////////////
+
+/// Format the reply of a panel:  the answer of each member, one after the other under a heading.
+fn format_comparison(
+    panel: &str,
+    message: impl AsRef<[u8]>,
+    answers: &[(String, String)],
+) -> Result<String, std::io::Error> {
+    let mut email = format_reply(panel, message)?;
+    email.push_str("\n\n");
+    for (member, answer) in answers.iter() {
+        let heading = format!("{} ", member);
+        email += &format!("{:=<72}\n\n", heading);
+        email += answer.trim_end();
+        email.push_str("\n\n");
+    }
+    email.truncate(email.trim_end().len());
+    email.push('\n');
+    Ok(email)
+}
+
+//////////////////////////////////////////// references ////////////////////////////////////////////
+
+/// The References of a reply to a message with `headers`:  the message's References, or its
+/// In-Reply-To if it has no References but names a single parent, followed by its Message-ID.
+///
+/// Long chains keep the first reference, which names the start of the thread, and the most recent
+/// ones, so that the header stays a reasonable size.
+fn references(headers: &[Header], msg_id: Option<&str>) -> Vec<String> {
+    let mut references = headers
+        .iter()
+        .filter_map(|header| match header {
+            Header::References(x) => Some(thread::split_msg_ids(x)),
+            _ => None,
+        })
+        .flatten()
+        .collect::<Vec<_>>();
+    if references.is_empty() {
+        let in_reply_to = headers
             .iter()
-            .map(|header| match header {
-                Header::From(x) => format!("From: {}", x),
-                Header::To(x) => format!("To: {}", x),
-                Header::Cc(x) => format!("Cc: {}", x),
-                Header::Subject(x) => format!("Subject: {}", x),
-                Header::Date(x) => format!("Date: {}", x),
-                Header::MessageID(x) => format!("Message-ID: {}", x),
-                Header::MimeVersion => "MIME-Version: 1.0".to_string(),
-                // We accept ascii, we emit utf-8
-                Header::ContentType => "Content-Type: text/plain; charset=utf-8".to_string(),
-                Header::References(x) => format!("References: {}", x),
-                Header::ContentDisposition => "Content-Disposition: inline".to_string(),
-                Header::InReplyTo(x) => format!("In-Reply-To: {}", x),
+            .filter_map(|header| match header {
+                Header::InReplyTo(x) => Some(thread::split_msg_ids(x)),
+                _ => None,
             })
-            .collect::<Vec<_>>()
-            .join("\n"),
-        body
-    ))
+            .flatten()
+            .collect::<Vec<_>>();
+        if in_reply_to.len() == 1 {
+            references = in_reply_to;
+        }
+    }
+    references.extend(msg_id.map(String::from));
+    let mut seen = std::collections::HashSet::new();
+    references.retain(|x| seen.insert(x.clone()));
+    if references.len() > MAX_REFERENCES {
+        references.drain(1..references.len() + 1 - MAX_REFERENCES);
+    }
+    references
+}
+
+/////////////////////////////////////////////// hops ///////////////////////////////////////////////
+
+/// How many replies maildir-ai has written in a row to reach a message with `headers`.
+///
+/// Mail clients don't copy X-AI-Hops into the user's replies, so a person's reply starts the count
+/// again however long the thread is.
+fn hops(headers: &[Header]) -> usize {
+    headers
+        .iter()
+        .find_map(|header| match header {
+            Header::Other(name, value) if name.eq_ignore_ascii_case(X_AI_HOPS) => {
+                value.trim().parse::<usize>().ok()
+            }
+            _ => None,
+        })
+        .unwrap_or(0)
 }
 
 //////////////////////////////////////////// wrap_header ///////////////////////////////////////////
 
 fn wrap_header(s: impl AsRef<str>) -> String {
-    let mut s = s.as_ref().to_string();
+    // Fold at the last space that keeps the line within 70 characters, or at the first space
+    // after if a single word is longer than that.
+    let mut s = s.as_ref().trim();
     let mut wrapped = String::new();
-    while s.len() > 70 {
-        let split_at = s
-            .chars()
-            .enumerate()
+    while s.chars().count() > 70 {
+        let spaces = s
+            .char_indices()
             .filter(|(_, c)| *c == ' ')
-            .last()
             .map(|(i, _)| i)
-            .unwrap_or(s.chars().count());
-        let this_line = s.chars().take(split_at).collect::<String>();
-        if wrapped.is_empty() {
-            wrapped = this_line.trim().to_string();
-        } else {
-            wrapped.push_str(&format!("\n {}", this_line.trim()));
+            .collect::<Vec<_>>();
+        let limit = s.char_indices().nth(70).map(|(i, _)| i).unwrap_or(s.len());
+        let Some(split_at) = spaces
+            .iter()
+            .copied()
+            .rfind(|i| *i > 0 && *i <= limit)
+            .or_else(|| spaces.iter().copied().find(|i| *i > 0))
+        else {
+            break;
+        };
+        if !wrapped.is_empty() {
+            wrapped.push_str("\n ");
         }
-        let remainder = s.chars().skip(split_at).collect::<String>();
-        s = remainder.trim().to_string();
+        wrapped.push_str(s[..split_at].trim());
+        s = s[split_at..].trim();
     }
     if !s.is_empty() {
-        wrapped.push_str(&s);
+        if !wrapped.is_empty() {
+            wrapped.push_str("\n ");
+        }
+        wrapped.push_str(s);
     }
     wrapped
 }
 
 //////////////////////////////////////// generate_message_id ///////////////////////////////////////
 
-/// Generate a message ID for the current host.
+/// Generates the Message-ID of each message maildir-ai writes.
+pub trait MessageIdGenerator: Send + Sync {
+    /// A new Message-ID, angle brackets included.
+    fn generate(&self) -> String;
+}
+
+impl<F: Fn() -> String + Send + Sync> MessageIdGenerator for F {
+    fn generate(&self) -> String {
+        self()
+    }
+}
+
+/// The default generator.  IDs combine the time in nanoseconds, 64 random bits, the process ID, and
+/// the hostname, so replies written in the same instant, or by two daemons, never share an ID.
+#[derive(Clone, Debug, Default)]
+pub struct UniqueMessageIds;
+
+impl MessageIdGenerator for UniqueMessageIds {
+    fn generate(&self) -> String {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .expect("time should go forwards only");
+        let hostname = maildir::hostname()
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '.')
+            .collect::<String>();
+        format!(
+            "<{}.{:09}.{:016x}.{}@{}>",
+            now.as_secs(),
+            now.subsec_nanos(),
+            getrandom::u64().unwrap_or_default(),
+            std::process::id(),
+            if hostname.is_empty() {
+                "localhost"
+            } else {
+                &hostname
+            },
+        )
+    }
+}
+
+static MESSAGE_ID_GENERATOR: std::sync::RwLock<Option<Box<dyn MessageIdGenerator>>> =
+    std::sync::RwLock::new(None);
+
+thread_local! {
+    static SCOPED_MESSAGE_ID_GENERATOR: std::cell::RefCell<Option<Box<dyn MessageIdGenerator>>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// Replace the generator behind [generate_message_id] for the whole process.
+pub fn set_message_id_generator(generator: impl MessageIdGenerator + 'static) {
+    *MESSAGE_ID_GENERATOR
+        .write()
+        .unwrap_or_else(|err| err.into_inner()) = Some(Box::new(generator));
+}
+
+/// Replace the generator behind [generate_message_id] on this thread only, e.g. with a
+/// deterministic one for a test, until the returned guard is dropped.  Other threads are unaffected.
+pub fn scoped_message_id_generator(
+    generator: impl MessageIdGenerator + 'static,
+) -> ScopedMessageIdGenerator {
+    let previous =
+        SCOPED_MESSAGE_ID_GENERATOR.with(|scoped| scoped.replace(Some(Box::new(generator))));
+    ScopedMessageIdGenerator {
+        previous,
+        _not_send: std::marker::PhantomData,
+    }
+}
+
+/// Puts back the generator a thread used before [scoped_message_id_generator] when dropped, even
+/// if the thread is panicking.
+#[must_use]
+pub struct ScopedMessageIdGenerator {
+    previous: Option<Box<dyn MessageIdGenerator>>,
+    // The guard restores the generator of the thread that made it, so it must stay there.
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+impl Drop for ScopedMessageIdGenerator {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        SCOPED_MESSAGE_ID_GENERATOR.with(|scoped| *scoped.borrow_mut() = previous);
+    }
+}
+
+/// Generate a globally unique message ID.
 pub fn generate_message_id() -> String {
-    format!(
-        "<{}@{}>",
-        chrono::Utc::now().timestamp(),
-        std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_string())
-    )
+    let scoped = SCOPED_MESSAGE_ID_GENERATOR.with(|scoped| {
+        scoped
+            .borrow()
+            .as_ref()
+            .map(|generator| generator.generate())
+    });
+    if let Some(msg_id) = scoped {
+        return msg_id;
+    }
+    match MESSAGE_ID_GENERATOR
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .as_ref()
+    {
+        Some(generator) => generator.generate(),
+        None => UniqueMessageIds.generate(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_block_round_trips() {
+        let block = "From: Alice <alice@example.org>\n\
+                     To: bob@example.org\n\
+                     Subject: hello\n\
+                     Date: Mon, 1 Jan 2024 00:00:00 +0000\n\
+                     Message-ID: <one@example.org>\n\
+                     X-Custom: kept as is\n\
+                     Content-Type: text/plain; charset=utf-8";
+        let headers = Header::from_block(block).unwrap();
+        assert_eq!(7, headers.len());
+        assert_eq!(
+            Header::Other("X-Custom".to_string(), "kept as is".to_string()),
+            headers[5]
+        );
+        assert_eq!(block, Header::to_block(&headers));
+        assert_eq!(
+            headers,
+            Header::from_block(Header::to_block(&headers)).unwrap()
+        );
+    }
+
+    #[test]
+    fn header_block_is_written_canonically() {
+        let block = "content-type: text/plain; charset=\"us-ascii\" (plain); foo\n\
+                     x-custom:  kept\n\
+                     \x20as is";
+        let headers = Header::from_block(block).unwrap();
+        let written = Header::to_block(&headers);
+        assert_eq!(
+            "Content-Type: text/plain; charset=us-ascii\n\
+             x-custom: kept as is",
+            written
+        );
+        assert_eq!(headers, Header::from_block(&written).unwrap());
+    }
+
+    #[test]
+    fn header_names_ignore_case() {
+        let headers = Header::from_block("content-type: text/html\nsUbJeCt: hi").unwrap();
+        assert_eq!(
+            vec![
+                Header::ContentType(ParameterizedValue::new("text/html")),
+                Header::Subject("hi".to_string()),
+            ],
+            headers
+        );
+        assert_eq!(
+            "Content-Type: text/html\nSubject: hi",
+            Header::to_block(&headers)
+        );
+    }
+
+    #[test]
+    fn header_continuation_lines_unfold() {
+        let headers =
+            Header::from_block("Subject: a long\n subject\nX-Folded: one\n\ttwo").unwrap();
+        assert_eq!(
+            vec![
+                Header::Subject("a long subject".to_string()),
+                Header::Other("X-Folded".to_string(), "one\ttwo".to_string()),
+            ],
+            headers
+        );
+    }
+
+    #[test]
+    fn header_lines_that_are_not_headers_are_skipped() {
+        let headers = Header::from_block("not a header\nSubject: kept\n: no name").unwrap();
+        assert_eq!(vec![Header::Subject("kept".to_string())], headers);
+    }
+
+    #[test]
+    fn header_parameters_round_trip() {
+        let headers = Header::from_block(
+            "Content-Type: text/plain; charset=\"utf-8\"; format=flowed\n\
+             Content-Disposition: attachment; filename=\"my notes.txt\"",
+        )
+        .unwrap();
+        let Header::ContentType(content_type) = &headers[0] else {
+            panic!("expected Content-Type, got {:?}", headers[0]);
+        };
+        assert!(content_type.is("TEXT/PLAIN"));
+        assert_eq!(Some("utf-8"), content_type.param("Charset"));
+        assert_eq!(Some("flowed"), content_type.param("format"));
+        assert_eq!(
+            "Content-Type: text/plain; charset=utf-8; format=flowed\n\
+             Content-Disposition: attachment; filename=\"my notes.txt\"",
+            Header::to_block(&headers)
+        );
+        assert_eq!(
+            headers,
+            Header::from_block(Header::to_block(&headers)).unwrap()
+        );
+    }
+
+    #[test]
+    fn header_keeps_content_type_with_valueless_parameter() {
+        let headers =
+            Header::from_block("Content-Type: multipart/mixed; boundary=\"XX\"; foo").unwrap();
+        assert_eq!(
+            vec![Header::ContentType(
+                ParameterizedValue::new("multipart/mixed").with_param("boundary", "XX")
+            )],
+            headers
+        );
+        let message = Message::parse(
+            "Content-Type: multipart/mixed; boundary=\"XX\"; foo\n\n\
+             --XX\n\
+             Content-Type: text/plain\n\n\
+             Hello.\n\
+             --XX--\n",
+        );
+        assert_eq!("Hello.", message.text);
+    }
+
+    #[test]
+    fn long_subjects_are_folded_between_encoded_words() {
+        let subject = format!("Re: {}", "\u{6f22}\u{5b57}".repeat(80));
+        let line = Header::Subject(subject.clone()).to_string();
+        assert!(line.lines().count() > 1);
+        assert!(line.lines().all(|line| line.len() <= 78));
+        assert_eq!(
+            vec![Header::Subject(subject)],
+            Header::from_block(&line).unwrap()
+        );
+    }
+
+    /// An empty knowledge base with the standard folders, under the system's temporary directory.
+    pub(crate) fn scratch_knowledge_base(name: &str) -> Path<'static> {
+        let root = std::env::temp_dir().join(format!("maildir-ai-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let root = Path::try_from(root).unwrap().into_owned();
+        for level1 in &[ARCHIVE, DRAFTS, INBOX, QUARANTINE, SENT, TRASH] {
+            for level2 in &[CUR, NEW, TMP] {
+                std::fs::create_dir_all(root.join(*level1).join(*level2)).unwrap();
+            }
+        }
+        root
+    }
+
+    /// Put `email` in the cur/ of `folder` as `name`, making the folder if need be.
+    pub(crate) fn file_message(knowledge_base: &Path<'_>, folder: &str, name: &str, email: &str) {
+        for level2 in &[CUR, NEW, TMP] {
+            std::fs::create_dir_all(knowledge_base.join(folder).join(*level2)).unwrap();
+        }
+        std::fs::write(knowledge_base.join(folder).join(CUR).join(name), email).unwrap();
+    }
+
+    fn prompt_with_id(msg_id: &str) -> String {
+        format!(
+            "From: Alice <alice@example.org>\n\
+             To: llama3@rave\n\
+             Subject: hello\n\
+             Message-ID: {}\n\
+             \n\
+             Hello.\n",
+            msg_id
+        )
+    }
+
+    #[test]
+    fn prompts_filed_in_any_folder_are_not_withdrawn() {
+        let kb = scratch_knowledge_base("filed");
+        let mut seen = Seen::new(&kb);
+        let prompts = vec![
+            ("<filed@example.org>".to_string(), "1.a".to_string()),
+            ("<deleted@example.org>".to_string(), "2.a".to_string()),
+            ("<waiting@example.org>".to_string(), "3.a".to_string()),
+        ];
+        let email = prompt_with_id("<filed@example.org>");
+        file_message(&kb, "Projects", "1.a:2,S", &email);
+        file_message(
+            &kb,
+            INBOX,
+            "3.a:2,S",
+            &prompt_with_id("<waiting@example.org>"),
+        );
+        assert_eq!(
+            vec![(
+                "<deleted@example.org>".to_string(),
+                "was deleted".to_string()
+            )],
+            seen.withdrawn(&kb, false, &prompts)
+        );
+        // Once read, a filed message is remembered rather than read again.
+        assert_eq!(
+            Some(&Some("<filed@example.org>".to_string())),
+            seen.filed
+                .get(&kb.join("Projects").join(CUR).join("1.a:2,S").into_owned())
+        );
+        std::fs::remove_dir_all(&kb).unwrap();
+    }
+
+    #[test]
+    fn prompts_moved_to_trash_are_withdrawn() {
+        let kb = scratch_knowledge_base("trashed");
+        file_message(&kb, TRASH, "0.a:2,S", &prompt_with_id("<old@example.org>"));
+        let mut seen = Seen::new(&kb);
+        let prompts = vec![("<new@example.org>".to_string(), "1.a".to_string())];
+        file_message(&kb, INBOX, "1.a:2,S", &prompt_with_id("<new@example.org>"));
+        assert!(seen.withdrawn(&kb, false, &prompts).is_empty());
+        file_message(&kb, TRASH, "4.b:2,S", &prompt_with_id("<new@example.org>"));
+        assert_eq!(
+            vec![(
+                "<new@example.org>".to_string(),
+                "was moved to Trash".to_string()
+            )],
+            seen.withdrawn(&kb, false, &prompts)
+        );
+        assert!(seen.withdrawn(&kb, false, &prompts).is_empty());
+        std::fs::remove_dir_all(&kb).unwrap();
+    }
+
+    fn scratch_context(knowledge_base: &Path<'_>) -> Context {
+        let (_, cancelled) = tokio::sync::watch::channel(false);
+        Context {
+            journal: Journal::open(knowledge_base).unwrap(),
+            backlog: Backlog::default(),
+            scheduler: Scheduler::new(1, 1),
+            in_flight: InFlight::default(),
+            spawned: Arc::default(),
+            cancelled,
+        }
+    }
+
+    #[test]
+    fn prompts_that_cannot_be_moved_stay_in_sent_unjournaled() {
+        let kb = scratch_knowledge_base("dispatch");
+        let context = scratch_context(&kb);
+        let config = Config {
+            model_domains: vec!["rave".to_string()],
+            ..Config::default()
+        };
+        let email = prompt_with_id("<stuck@example.org>");
+        file_message(&kb, SENT, "1.a:2,S", &email);
+        std::fs::remove_dir_all(kb.join(INBOX)).unwrap();
+        let path = kb.join(SENT).join(CUR).join("1.a:2,S");
+        let mut tasks = JoinSet::new();
+        let result = dispatch(
+            &MaintainOptions::default(),
+            &config,
+            &kb,
+            &context,
+            &mut tasks,
+            &path,
+        );
+        assert!(matches!(result, Err(Undispatched::Failed(_))));
+        assert!(path.clone().into_std().is_file());
+        assert!(context.journal.entries().unwrap().is_empty());
+        assert!(tasks.is_empty());
+        std::fs::remove_dir_all(&kb).unwrap();
+    }
+
+    #[test]
+    fn prompts_without_recipients_are_refused() {
+        let kb = scratch_knowledge_base("refused");
+        let context = scratch_context(&kb);
+        file_message(&kb, SENT, "1.a:2,S", "Subject: nobody\n\nHello.\n");
+        let path = kb.join(SENT).join(CUR).join("1.a:2,S");
+        let result = dispatch(
+            &MaintainOptions::default(),
+            &Config::default(),
+            &kb,
+            &context,
+            &mut JoinSet::new(),
+            &path,
+        );
+        assert!(matches!(result, Err(Undispatched::Refused(_))));
+        std::fs::remove_dir_all(&kb).unwrap();
+    }
+
+    fn with_attachment(text: &str, filename: &str, attachment: &str) -> String {
+        format!(
+            "Content-Type: multipart/mixed; boundary=\"b\"\n\n\
+             --b\n\
+             Content-Type: text/plain\n\n\
+             {}\n\
+             --b\n\
+             Content-Type: text/plain\n\
+             Content-Disposition: attachment; filename=\"{}\"\n\n\
+             {}\n\
+             --b--\n",
+            text, filename, attachment
+        )
+    }
+
+    #[test]
+    fn attachments_fit_within_the_context_budget() {
+        let message = with_attachment("See the log.", "run.log", "one\ntwo\nthree\n");
+        let mut budget = 1024;
+        let prompt = extract_prompt(&message, &mut budget);
+        assert_eq!(
+            "See the log.\n\
+             \n\
+             --- attachment: run.log (text/plain) ---\n\
+             one\n\
+             two\n\
+             three\n\
+             --- end of attachment ---\n",
+            prompt
+        );
+        assert_eq!(1024 - "one\ntwo\nthree\n".len(), budget);
+    }
+
+    #[test]
+    fn attachments_are_truncated_at_a_line_and_noted() {
+        let message = with_attachment("See the log.", "run.log", "one\ntwo\nthree\n");
+        let mut budget = 10;
+        let prompt = extract_prompt(&message, &mut budget);
+        assert_eq!(
+            "See the log.\n\
+             \n\
+             --- attachment: run.log (text/plain) ---\n\
+             one\n\
+             two\n\
+             --- end of attachment ---\n\
+             \n\
+             [note: attachment run.log (text/plain) was truncated to 8 of 14 bytes to fit the context budget]\n",
+            prompt
+        );
+        assert_eq!(2, budget);
+    }
+
+    #[test]
+    fn context_budget_is_shared_across_messages() {
+        let newest = with_attachment("Newer.", "new.log", "new\n");
+        let oldest = with_attachment("Older.", "old.log", "old\n");
+        let mut budget = 4;
+        assert!(extract_prompt(&newest, &mut budget).contains("new\n--- end of attachment"));
+        assert_eq!(0, budget);
+        assert_eq!(
+            "Older.\n\
+             \n\
+             [note: attachment old.log (text/plain) was left out to fit the context budget]\n",
+            extract_prompt(&oldest, &mut budget)
+        );
+    }
+
+    #[test]
+    fn thread_roles_follow_the_sender() {
+        let kb = scratch_knowledge_base("roles");
+        file_message(
+            &kb,
+            INBOX,
+            "1.a:2,S",
+            "From: Alice <alice@example.org>\n\
+             To: coder@rave\n\
+             Message-ID: <1@example.org>\n\
+             \n\
+             First question.\n",
+        );
+        file_message(
+            &kb,
+            INBOX,
+            "2.a:2,S",
+            "From: coder@rave\n\
+             To: Alice <alice@example.org>\n\
+             Message-ID: <2@example.org>\n\
+             In-Reply-To: <1@example.org>\n\
+             \n\
+             First answer.\n",
+        );
+        let email = "From: Alice <alice@example.org>\n\
+                     To: coder@rave\n\
+                     Message-ID: <3@example.org>\n\
+                     In-Reply-To: <2@example.org>\n\
+                     References: <1@example.org> <2@example.org>\n\
+                     \n\
+                     Second question.\n";
+        let config = Config::default();
+        let messages = prompt_messages(&config, &kb, "coder@rave", email.as_bytes()).unwrap();
+        let conversation = messages
+            .iter()
+            .map(|message| (message.role.as_str(), message.content.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                ("user", "First question.\n"),
+                ("assistant", "First answer.\n"),
+                ("user", "Second question.\n"),
+            ],
+            conversation
+        );
+        std::fs::remove_dir_all(&kb).unwrap();
+    }
+
+    #[test]
+    fn context_budget_goes_to_the_newest_message_first() {
+        let kb = scratch_knowledge_base("budget");
+        let first = format!(
+            "From: Alice <alice@example.org>\n\
+             Message-ID: <1@example.org>\n\
+             {}",
+            with_attachment("First question.", "first.log", "first\n")
+        );
+        file_message(&kb, INBOX, "1.a:2,S", &first);
+        let email = format!(
+            "From: Alice <alice@example.org>\n\
+             Message-ID: <2@example.org>\n\
+             In-Reply-To: <1@example.org>\n\
+             {}",
+            with_attachment("Second question.", "second.log", "second\n")
+        );
+        let config = Config {
+            context_budget: 7,
+            ..Config::default()
+        };
+        let messages = prompt_messages(&config, &kb, "coder@rave", email.as_bytes()).unwrap();
+        assert_eq!(2, messages.len());
+        assert!(messages[0]
+            .content
+            .contains("[note: attachment first.log (text/plain) was left out"));
+        assert!(messages[1]
+            .content
+            .contains("second\n--- end of attachment"));
+        std::fs::remove_dir_all(&kb).unwrap();
+    }
+
+    fn thread_message(depth: usize, extra: &str) -> String {
+        let references = (0..depth)
+            .map(|n| format!("<{}@example.org>", n))
+            .collect::<Vec<_>>()
+            .join(" ");
+        format!(
+            "From: Alice <alice@example.org>\n\
+             To: llama3@rave\n\
+             Subject: Re: hello\n\
+             Date: Mon, 1 Jan 2024 00:00:00 +0000\n\
+             Message-ID: <{}@example.org>\n\
+             In-Reply-To: <{}@example.org>\n\
+             References: {}\n\
+             {}\n\
+             Hello.\n",
+            depth,
+            depth - 1,
+            references,
+            extra,
+        )
+    }
+
+    #[test]
+    fn long_user_threads_are_answered() {
+        let config = Config::default();
+        let message = thread_message(20, "");
+        assert_eq!(None, refuse(&config, message.as_bytes()));
+        let reply = format_reply("llama3@rave", &message).unwrap();
+        assert!(reply.contains("\nX-AI-Hops: 1\n"));
+    }
+
+    #[test]
+    fn replies_answered_too_many_times_are_refused() {
+        let config = Config::default();
+        let message = thread_message(3, &format!("X-AI-Hops: {}\n", config.max_hops));
+        assert_eq!(None, refuse(&config, message.as_bytes()));
+        let message = thread_message(3, &format!("X-AI-Hops: {}\n", config.max_hops + 1));
+        assert!(refuse(&config, message.as_bytes()).is_some());
+        let message = thread_message(3, "X-Loop: maildir-ai\n");
+        assert!(refuse(&config, message.as_bytes()).is_some());
+    }
+
+    fn msg_ids(range: std::ops::Range<usize>) -> Vec<String> {
+        range.map(|n| format!("<{}@example.org>", n)).collect()
+    }
+
+    #[test]
+    fn references_keep_the_first_and_the_most_recent() {
+        let headers = vec![Header::References(msg_ids(0..30).join(" "))];
+        let mut expected = msg_ids(0..1);
+        expected.extend(msg_ids(12..31));
+        assert_eq!(MAX_REFERENCES, expected.len());
+        assert_eq!(expected, references(&headers, Some("<30@example.org>")));
+    }
+
+    #[test]
+    fn references_fall_back_to_a_single_in_reply_to() {
+        let headers = vec![Header::InReplyTo("<parent@example.org>".to_string())];
+        assert_eq!(
+            vec!["<parent@example.org>", "<child@example.org>"],
+            references(&headers, Some("<child@example.org>"))
+        );
+        // With several candidates it is unclear which is the parent, so none is used.
+        let headers = vec![Header::InReplyTo(
+            "<a@example.org> <b@example.org>".to_string(),
+        )];
+        assert_eq!(
+            vec!["<child@example.org>"],
+            references(&headers, Some("<child@example.org>"))
+        );
+        // References win when there are both, and repeats are dropped.
+        let headers = vec![
+            Header::InReplyTo("<b@example.org>".to_string()),
+            Header::References("<a@example.org> <b@example.org> <a@example.org>".to_string()),
+        ];
+        assert_eq!(
+            vec!["<a@example.org>", "<b@example.org>"],
+            references(&headers, None)
+        );
+    }
+
+    #[test]
+    fn replies_use_the_installed_message_id_generator() {
+        let _scope = scoped_message_id_generator(|| "<fixed@example.org>".to_string());
+        let message = "From: Alice <alice@example.org>\n\
+                       To: llama3@rave\n\
+                       Subject: hello\n\
+                       Date: Mon, 1 Jan 2024 00:00:00 +0000\n\
+                       Message-ID: <two@example.org>\n\
+                       References: <one@example.org>\n\
+                       \n\
+                       Hello.\n";
+        let reply = format_reply("llama3@rave", message).unwrap();
+        let headers = Header::from_block(reply.split("\n\n").next().unwrap()).unwrap();
+        assert!(headers.contains(&Header::MessageID("<fixed@example.org>".to_string())));
+        assert!(headers.contains(&Header::InReplyTo("<two@example.org>".to_string())));
+        assert!(headers.contains(&Header::References(
+            "<one@example.org> <two@example.org>".to_string()
+        )));
+        assert!(headers.contains(&Header::Subject("Re: hello".to_string())));
+    }
+
+    #[test]
+    fn scoped_message_id_generators_restore_the_previous_one() {
+        let outer = scoped_message_id_generator(|| "<outer@example.org>".to_string());
+        {
+            let _inner = scoped_message_id_generator(|| "<inner@example.org>".to_string());
+            assert_eq!("<inner@example.org>", generate_message_id());
+            // Other threads keep their own generator.
+            let elsewhere = std::thread::spawn(generate_message_id).join().unwrap();
+            assert_ne!("<inner@example.org>", elsewhere);
+        }
+        assert_eq!("<outer@example.org>", generate_message_id());
+        drop(outer);
+        assert_ne!("<outer@example.org>", generate_message_id());
+    }
 }
@@ -0,0 +1,100 @@
+//! Deliver messages to a maildir the way maildir(5) describes:  write to tmp/, then rename to new/.
+
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use utf8path::Path;
+
+use super::{CUR, NEW, TMP};
+
+/// Distinguishes deliveries made by this process within the same microsecond.
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+////////////////////////////////////////////// deliver /////////////////////////////////////////////
+
+/// Deliver `message` to the maildir at `maildir`, returning the path it was delivered to.
+///
+/// The message is written and fsync'd under tmp/ before it is renamed into new/, so readers never
+/// see a partial message.  It has no info suffix; the mail client adds flags when it moves the
+/// message to cur/.
+pub fn deliver(
+    maildir: &Path<'_>,
+    message: impl AsRef<[u8]>,
+) -> Result<Path<'static>, std::io::Error> {
+    let name = unique_name();
+    let tmp = maildir.join(TMP).join(&name);
+    let new = maildir.join(NEW).join(&name);
+    let write = || -> Result<(), std::io::Error> {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp)?;
+        file.write_all(message.as_ref())?;
+        file.sync_all()
+    };
+    if let Err(err) = write() {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(err);
+    }
+    std::fs::rename(&tmp, &new)?;
+    std::fs::File::open(maildir.join(NEW))?.sync_all()?;
+    Ok(new.into_owned())
+}
+
+/////////////////////////////////////////////// find ///////////////////////////////////////////////
+
+/// Find the message named `name` in the cur/ or new/ of `maildir`.  Names are compared without their
+/// info suffix, so a message is found even after the mail client changes its flags.
+pub fn find(maildir: &Path<'_>, name: &str) -> Option<Path<'static>> {
+    let unique = unique_part(name);
+    for level2 in &[CUR, NEW] {
+        let Ok(dir) = std::fs::read_dir(maildir.join(*level2)) else {
+            continue;
+        };
+        for dirent in dir.flatten() {
+            let Ok(path) = Path::try_from(dirent.path()) else {
+                continue;
+            };
+            if unique_part(path.basename().as_str()) == unique {
+                return Some(path.into_owned());
+            }
+        }
+    }
+    None
+}
+
+/// The part of a maildir name that identifies the message, i.e. everything before the info suffix.
+pub fn unique_part(name: &str) -> &str {
+    name.split(':').next().unwrap_or(name)
+}
+
+/////////////////////////////////////////////// names //////////////////////////////////////////////
+
+/// A name no other delivery will use:  `<secs>.M<usecs>P<pid>Q<counter>.<hostname>`.
+pub fn unique_name() -> String {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("time should go forwards only");
+    format!(
+        "{}.M{}P{}Q{}.{}",
+        now.as_secs(),
+        now.subsec_micros(),
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        escape_hostname(&hostname()),
+    )
+}
+
+/// The name of this host as the system knows it.
+pub fn hostname() -> String {
+    match gethostname::gethostname().into_string() {
+        Ok(hostname) if !hostname.is_empty() => hostname,
+        _ => "localhost".to_string(),
+    }
+}
+
+// maildir(5) reserves `/` and `:` in names, so they are written as octal escapes.
+fn escape_hostname(hostname: &str) -> String {
+    hostname.replace('/', "\\057").replace(':', "\\072")
+}
@@ -0,0 +1,594 @@
+//! A MIME layer that walks multipart trees and decodes transfer encodings.
+
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::DecodePaddingMode;
+use base64::Engine;
+
+use super::{rfc2047, Header, ParameterizedValue};
+
+/// A lenient decoder for base64 bodies:  padding is optional and trailing bits are ignored.
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &base64::alphabet::STANDARD,
+    GeneralPurposeConfig::new()
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent)
+        .with_decode_allow_trailing_bits(true),
+);
+
+//////////////////////////////////////////////// Part //////////////////////////////////////////////
+
+/// One node of a MIME tree.
+#[derive(Clone, Debug, Default)]
+pub struct Part {
+    /// The headers of this part.
+    pub headers: Vec<Header>,
+    /// The body of this part with its transfer encoding removed.  Empty for multipart parts.
+    pub body: Vec<u8>,
+    /// The children of a multipart part.
+    pub parts: Vec<Part>,
+}
+
+impl Part {
+    /// Parse a part from its raw bytes.
+    pub fn parse(raw: impl AsRef<[u8]>) -> Self {
+        let raw = raw.as_ref();
+        let headers = parse_headers(raw);
+        let (_, body) = split_header_block(raw);
+        let mut part = Self {
+            headers,
+            body: vec![],
+            parts: vec![],
+        };
+        let content_type = part.content_type();
+        if content_type
+            .value
+            .to_ascii_lowercase()
+            .starts_with("multipart/")
+        {
+            if let Some(boundary) = content_type.param("boundary") {
+                part.parts = split_multipart(body, boundary)
+                    .into_iter()
+                    .map(Part::parse)
+                    .collect();
+                return part;
+            }
+        }
+        part.body = match part.transfer_encoding().as_str() {
+            "base64" => decode_base64(body),
+            "quoted-printable" => decode_quoted_printable(body),
+            _ => body.to_vec(),
+        };
+        part
+    }
+
+    /// The content type of this part, defaulting to `text/plain; charset=us-ascii`.
+    pub fn content_type(&self) -> ParameterizedValue {
+        self.headers
+            .iter()
+            .find_map(|header| match header {
+                Header::ContentType(x) => Some(x.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| {
+                ParameterizedValue::new("text/plain").with_param("charset", "us-ascii")
+            })
+    }
+
+    /// The lowercased content transfer encoding of this part, defaulting to `7bit`.
+    pub fn transfer_encoding(&self) -> String {
+        self.headers
+            .iter()
+            .find_map(|header| match header {
+                Header::ContentTransferEncoding(x) => Some(x.trim().to_ascii_lowercase()),
+                _ => None,
+            })
+            .unwrap_or_else(|| "7bit".to_string())
+    }
+
+    /// The file name of this part, if it has one.
+    pub fn filename(&self) -> Option<String> {
+        let content_type = self.content_type();
+        let disposition = self.headers.iter().find_map(|header| match header {
+            Header::ContentDisposition(x) => Some(x),
+            _ => None,
+        });
+        disposition
+            .and_then(|x| x.param("filename*").map(decode_extended_value))
+            .or_else(|| disposition.and_then(|x| x.param("filename").map(rfc2047::decode)))
+            .or_else(|| content_type.param("name*").map(decode_extended_value))
+            .or_else(|| content_type.param("name").map(rfc2047::decode))
+    }
+
+    /// True if this part was explicitly marked as an attachment.
+    pub fn is_attachment(&self) -> bool {
+        self.headers.iter().any(|header| match header {
+            Header::ContentDisposition(x) => x.is("attachment"),
+            _ => false,
+        })
+    }
+
+    /// The body of this part as text, decoded according to its charset.
+    pub fn text(&self) -> String {
+        let content_type = self.content_type();
+        decode_charset(&self.body, content_type.param("charset")).replace("\r\n", "\n")
+    }
+}
+
+///////////////////////////////////////////// Attachment ///////////////////////////////////////////
+
+/// A part of a message that is not its text.
+#[derive(Clone, Debug)]
+pub struct Attachment {
+    /// The file name of the attachment, if it has one.
+    pub filename: Option<String>,
+    /// The lowercased MIME type of the attachment, e.g. `image/png`.
+    pub content_type: String,
+    /// The decoded content of the attachment.
+    pub body: Vec<u8>,
+}
+
+impl Attachment {
+    /// True if this attachment is text a model can read:  text/*, a textual application type, or a
+    /// file with an extension commonly used for source code, logs, and data.
+    pub fn is_text(&self) -> bool {
+        const TYPES: &[&str] = &[
+            "application/json",
+            "application/xml",
+            "application/javascript",
+            "application/x-sh",
+            "application/x-yaml",
+            "application/yaml",
+            "application/toml",
+            "application/sql",
+            "application/x-patch",
+            "application/x-diff",
+        ];
+        const EXTENSIONS: &[&str] = &[
+            "c", "cc", "cpp", "csv", "diff", "go", "h", "hpp", "java", "js", "json", "log", "md",
+            "patch", "py", "rb", "rs", "sh", "sql", "toml", "ts", "tsv", "txt", "xml", "yaml",
+            "yml",
+        ];
+        let extension = self
+            .filename
+            .as_ref()
+            .and_then(|f| f.rsplit_once('.'))
+            .map(|(_, ext)| ext.to_ascii_lowercase());
+        let textual = self.content_type.starts_with("text/")
+            || TYPES.contains(&self.content_type.as_str())
+            || extension.is_some_and(|ext| EXTENSIONS.contains(&ext.as_str()));
+        textual && !self.body.contains(&0)
+    }
+
+    /// A human-readable label for the attachment.
+    pub fn label(&self) -> String {
+        match &self.filename {
+            Some(filename) => format!("{} ({})", filename, self.content_type),
+            None => format!("unnamed ({})", self.content_type),
+        }
+    }
+}
+
+////////////////////////////////////////////// Message /////////////////////////////////////////////
+
+/// A message split into the text a person wrote and everything attached to it.
+#[derive(Clone, Debug, Default)]
+pub struct Message {
+    /// The top-level headers of the message.
+    pub headers: Vec<Header>,
+    /// The text of the message:  the first text/plain part, or the first text/html part rendered
+    /// as text if there is no plain text.
+    pub text: String,
+    /// Every other leaf part of the message.
+    pub attachments: Vec<Attachment>,
+}
+
+impl Message {
+    /// Parse a message from its raw bytes.
+    pub fn parse(raw: impl AsRef<[u8]>) -> Self {
+        let root = Part::parse(raw);
+        let mut text = None;
+        let mut attachments = vec![];
+        select(&root, &mut text, &mut attachments);
+        Self {
+            headers: root.headers,
+            text: text.unwrap_or_default(),
+            attachments,
+        }
+    }
+}
+
+fn select(part: &Part, text: &mut Option<String>, attachments: &mut Vec<Attachment>) {
+    let content_type = part.content_type();
+    let mime_type = content_type.value.to_ascii_lowercase();
+    if mime_type == "multipart/alternative" && !part.parts.is_empty() {
+        let rank = |p: &Part| match p.content_type().value.to_ascii_lowercase().as_str() {
+            "text/plain" => 0,
+            "text/html" => 2,
+            x if x.starts_with("multipart/") => 1,
+            _ => 3,
+        };
+        if let Some(best) = part.parts.iter().min_by_key(|p| rank(p)) {
+            select(best, text, attachments);
+        }
+    } else if mime_type.starts_with("multipart/") {
+        for child in part.parts.iter() {
+            select(child, text, attachments);
+        }
+    } else if text.is_none()
+        && !part.is_attachment()
+        && (mime_type == "text/plain" || mime_type == "text/html")
+    {
+        if mime_type == "text/html" {
+            *text = Some(html_to_text(&part.text()));
+        } else {
+            *text = Some(part.text());
+        }
+    } else {
+        attachments.push(Attachment {
+            filename: part.filename(),
+            content_type: mime_type,
+            body: part.body.clone(),
+        });
+    }
+}
+
+/////////////////////////////////////////////// utils //////////////////////////////////////////////
+
+/// Parse only the top-level headers of a raw message.
+pub(crate) fn parse_headers(raw: &[u8]) -> Vec<Header> {
+    let (header_block, _) = split_header_block(raw);
+    Header::from_block(decode_charset(header_block, None)).unwrap_or_default()
+}
+
+/// Read and parse only the top-level headers of the message at `path`, leaving its body unread.
+pub(crate) fn read_headers(
+    path: impl AsRef<std::path::Path>,
+) -> Result<Vec<Header>, std::io::Error> {
+    use std::io::BufRead;
+
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut raw = vec![];
+    loop {
+        let start = raw.len();
+        if reader.read_until(b'\n', &mut raw)? == 0 || matches!(&raw[start..], b"\n" | b"\r\n") {
+            break;
+        }
+    }
+    Ok(parse_headers(&raw))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn split_header_block(raw: &[u8]) -> (&[u8], &[u8]) {
+    let lf = find(raw, b"\n\n").map(|idx| (idx, idx + 2));
+    let crlf = find(raw, b"\r\n\r\n").map(|idx| (idx, idx + 4));
+    match (lf, crlf) {
+        (Some(lf), Some(crlf)) if crlf.0 < lf.0 => (&raw[..crlf.0], &raw[crlf.1..]),
+        (Some(lf), _) => (&raw[..lf.0], &raw[lf.1..]),
+        (None, Some(crlf)) => (&raw[..crlf.0], &raw[crlf.1..]),
+        (None, None) if raw.starts_with(b"\n") => (&[], &raw[1..]),
+        (None, None) => (raw, &[]),
+    }
+}
+
+fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts = vec![];
+    let mut start = None;
+    let mut offset = 0;
+    for line in body.split_inclusive(|b| *b == b'\n') {
+        let trimmed = line.trim_ascii_end();
+        if trimmed.starts_with(delimiter.as_bytes()) {
+            let rest = &trimmed[delimiter.len()..];
+            if rest.is_empty() || rest == b"--" {
+                if let Some(start) = start {
+                    // The line break before a delimiter belongs to the delimiter.
+                    let mut end = offset;
+                    if body[..end].ends_with(b"\r\n") {
+                        end -= 2;
+                    } else if body[..end].ends_with(b"\n") {
+                        end -= 1;
+                    }
+                    parts.push(&body[start..end.max(start)]);
+                }
+                if rest == b"--" {
+                    return parts;
+                }
+                start = Some(offset + line.len());
+            }
+        }
+        offset += line.len();
+    }
+    if let Some(start) = start {
+        parts.push(&body[start..]);
+    }
+    parts
+}
+
+/// Decode bytes in the named charset.  Text without a charset, or labeled US-ASCII, is taken to be
+/// UTF-8 when it is valid UTF-8 and windows-1252 otherwise, which is what 8-bit mail usually is.
+pub(crate) fn decode_charset(bytes: &[u8], charset: Option<&str>) -> String {
+    let charset = charset.map(|c| c.trim().to_ascii_lowercase());
+    let encoding = match charset.as_deref() {
+        None | Some("us-ascii") | Some("ascii") | Some("utf-8") | Some("utf8") => {
+            if let Ok(text) = std::str::from_utf8(bytes) {
+                return text.to_string();
+            }
+            encoding_rs::WINDOWS_1252
+        }
+        Some(label) => {
+            encoding_rs::Encoding::for_label(label.as_bytes()).unwrap_or(encoding_rs::UTF_8)
+        }
+    };
+    let (text, _, _) = encoding.decode(bytes);
+    text.into_owned()
+}
+
+// RFC 2231 extended values look like utf-8'en'na%C3%AFve.txt.
+fn decode_extended_value(value: &str) -> String {
+    let mut pieces = value.splitn(3, '\'');
+    let (Some(charset), Some(_), Some(text)) = (pieces.next(), pieces.next(), pieces.next()) else {
+        return value.to_string();
+    };
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let bytes = text.as_bytes();
+    let mut decoded = vec![];
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match (
+            bytes[idx],
+            bytes.get(idx + 1).copied().and_then(hex),
+            bytes.get(idx + 2).copied().and_then(hex),
+        ) {
+            (b'%', Some(hi), Some(lo)) => {
+                decoded.push(hi << 4 | lo);
+                idx += 3;
+            }
+            (b, _, _) => {
+                decoded.push(b);
+                idx += 1;
+            }
+        }
+    }
+    let charset = if charset.is_empty() {
+        None
+    } else {
+        Some(charset)
+    };
+    decode_charset(&decoded, charset)
+}
+
+pub(crate) fn decode_base64(body: &[u8]) -> Vec<u8> {
+    let cleaned = body
+        .iter()
+        .copied()
+        .filter(|b| b.is_ascii_alphanumeric() || *b == b'+' || *b == b'/')
+        .collect::<Vec<_>>();
+    BASE64.decode(cleaned).unwrap_or_else(|_| body.to_vec())
+}
+
+pub(crate) fn decode_quoted_printable(body: &[u8]) -> Vec<u8> {
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let mut decoded = Vec::with_capacity(body.len());
+    let mut idx = 0;
+    while idx < body.len() {
+        if body[idx] != b'=' {
+            decoded.push(body[idx]);
+            idx += 1;
+        } else if body[idx + 1..].starts_with(b"\r\n") {
+            idx += 3;
+        } else if body[idx + 1..].starts_with(b"\n") {
+            idx += 2;
+        } else if let (Some(hi), Some(lo)) = (
+            body.get(idx + 1).copied().and_then(hex),
+            body.get(idx + 2).copied().and_then(hex),
+        ) {
+            decoded.push(hi << 4 | lo);
+            idx += 3;
+        } else {
+            decoded.push(b'=');
+            idx += 1;
+        }
+    }
+    decoded
+}
+
+//////////////////////////////////////////// html_to_text //////////////////////////////////////////
+
+/// Render HTML as plain text.  This is not a browser; it keeps the words and the paragraph breaks.
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(lt) = rest.find('<') {
+        text.push_str(&rest[..lt]);
+        let Some(gt) = rest[lt..].find('>') else {
+            rest = &rest[lt..];
+            break;
+        };
+        let tag = rest[lt + 1..lt + gt].trim().to_ascii_lowercase();
+        rest = &rest[lt + gt + 1..];
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default();
+        match name {
+            "script" | "style" | "head" if !tag.starts_with('/') => {
+                let close = format!("</{}", name);
+                let end = rest.to_ascii_lowercase().find(&close).unwrap_or(rest.len());
+                rest = &rest[end..];
+            }
+            "br" => text.push('\n'),
+            "li" if !tag.starts_with('/') => text.push_str("\n* "),
+            "p" | "div" | "tr" | "ul" | "ol" | "blockquote" | "pre" | "h1" | "h2" | "h3" | "h4"
+            | "h5" | "h6" => text.push_str("\n\n"),
+            _ => {}
+        }
+    }
+    text.push_str(rest);
+    let text = decode_entities(&text);
+    let mut rendered = String::new();
+    let mut blank = false;
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() {
+            blank = true;
+            continue;
+        }
+        if blank && !rendered.is_empty() {
+            rendered.push('\n');
+        }
+        blank = false;
+        rendered.push_str(&line);
+        rendered.push('\n');
+    }
+    rendered
+}
+
+fn decode_entities(s: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        decoded.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let Some(semi) = rest.find(';').filter(|semi| *semi <= 12) else {
+            decoded.push('&');
+            rest = &rest[1..];
+            continue;
+        };
+        let entity = &rest[1..semi];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|x| u32::from_str_radix(x, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|x| x.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        if let Some(c) = c {
+            decoded.push(c);
+            rest = &rest[semi + 1..];
+        } else {
+            decoded.push('&');
+            rest = &rest[1..];
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_decodes_across_lines_without_padding() {
+        assert_eq!(
+            b"hello world".to_vec(),
+            decode_base64(b"aGVsbG8g\r\nd29ybGQ")
+        );
+        assert_eq!(
+            b"hello world".to_vec(),
+            decode_base64(b"aGVsbG8gd29ybGQ=\n")
+        );
+    }
+
+    #[test]
+    fn quoted_printable_decodes_escapes_and_soft_breaks() {
+        assert_eq!(
+            "caf\u{e9} au lait, s'il vous pla\u{ee}t"
+                .as_bytes()
+                .to_vec(),
+            decode_quoted_printable(b"caf=C3=A9 au lait, s'il vous=\r\n pla=C3=AEt")
+        );
+        // A stray equals sign is kept rather than dropped.
+        assert_eq!(b"1 = 1".to_vec(), decode_quoted_printable(b"1 = 1"));
+    }
+
+    #[test]
+    fn single_part_bodies_are_decoded() {
+        let message = Message::parse(
+            "Content-Type: text/plain; charset=iso-8859-1\n\
+             Content-Transfer-Encoding: quoted-printable\n\n\
+             Gr=FC=DFe\n",
+        );
+        assert_eq!("Gr\u{fc}\u{df}e\n", message.text);
+        assert!(message.attachments.is_empty());
+    }
+
+    #[test]
+    fn multipart_mixed_splits_text_and_attachments() {
+        let message = Message::parse(
+            "Content-Type: multipart/mixed; boundary=\"outer\"\n\n\
+             preamble\n\
+             --outer\n\
+             Content-Type: text/plain; charset=utf-8\n\
+             Content-Transfer-Encoding: base64\n\n\
+             SGVsbG8sIHdvcmxkIQ==\n\
+             --outer\n\
+             Content-Type: text/x-log\n\
+             Content-Disposition: attachment; filename=\"run.log\"\n\n\
+             line one\n\
+             --outer\n\
+             Content-Type: image/png; name=\"dot.png\"\n\
+             Content-Transfer-Encoding: base64\n\n\
+             iVBORw0K\n\
+             --outer--\n\
+             epilogue\n",
+        );
+        assert_eq!("Hello, world!", message.text);
+        assert_eq!(2, message.attachments.len());
+        assert_eq!(Some("run.log"), message.attachments[0].filename.as_deref());
+        assert_eq!(b"line one".to_vec(), message.attachments[0].body);
+        assert!(message.attachments[0].is_text());
+        assert_eq!("image/png", message.attachments[1].content_type);
+        assert_eq!(Some("dot.png"), message.attachments[1].filename.as_deref());
+        assert_eq!(b"\x89PNG\r\n".to_vec(), message.attachments[1].body);
+        assert!(!message.attachments[1].is_text());
+    }
+
+    #[test]
+    fn multipart_alternative_prefers_plain_text() {
+        let message = Message::parse(
+            "Content-Type: multipart/alternative; boundary=b\n\n\
+             --b\n\
+             Content-Type: text/html\n\n\
+             <p>rich</p>\n\
+             --b\n\
+             Content-Type: text/plain\n\n\
+             plain\n\
+             --b--\n",
+        );
+        assert_eq!("plain", message.text);
+        assert!(message.attachments.is_empty());
+    }
+
+    #[test]
+    fn html_only_messages_are_rendered_as_text() {
+        let message = Message::parse(
+            "Content-Type: text/html; charset=utf-8\n\n\
+             <html><body><p>Hello &amp; welcome</p></body></html>\n",
+        );
+        assert!(message.text.contains("Hello & welcome"));
+        assert!(!message.text.contains('<'));
+    }
+
+    #[test]
+    fn extended_filenames_are_decoded() {
+        let part = Part::parse(
+            "Content-Type: text/plain\n\
+             Content-Disposition: attachment; filename*=utf-8''na%C3%AFve.txt\n\n\
+             x\n",
+        );
+        assert_eq!(Some("na\u{ef}ve.txt".to_string()), part.filename());
+    }
+}
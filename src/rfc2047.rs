@@ -0,0 +1,223 @@
+//! RFC 2047 encoded words:  non-ASCII text in message headers.
+
+use super::mime::{decode_base64, decode_charset, decode_quoted_printable};
+
+/// The longest an encoded word may be.
+const MAX_ENCODED_WORD: usize = 75;
+
+/// Characters with meaning in structured headers that require a phrase to be quoted.
+const SPECIALS: &str = "()<>[]:;@\\,.\"";
+
+////////////////////////////////////////////// decoding ////////////////////////////////////////////
+
+/// Decode the encoded words in an unstructured header such as Subject.
+pub fn decode(s: &str) -> String {
+    decode_words(s, false)
+}
+
+/// Decode the encoded words in a structured header such as From or To.  Decoded text that contains
+/// specials is quoted so that it still parses as a single phrase.
+pub fn decode_structured(s: &str) -> String {
+    decode_words(s, true)
+}
+
+fn decode_words(s: &str, structured: bool) -> String {
+    let mut decoded = String::new();
+    // Whitespace between two encoded words is dropped, so hold it until we see what follows.
+    let mut pending_ws = String::new();
+    let mut last_was_encoded = false;
+    let mut run = String::new();
+    let flush_run = |decoded: &mut String, run: &mut String| {
+        if run.is_empty() {
+            return;
+        }
+        if structured && run.chars().any(|c| SPECIALS.contains(c)) {
+            decoded.push('"');
+            decoded.push_str(&run.replace('\\', "\\\\").replace('"', "\\\""));
+            decoded.push('"');
+        } else {
+            decoded.push_str(run);
+        }
+        run.clear();
+    };
+    let mut rest = s;
+    while !rest.is_empty() {
+        let ws_len = rest.len() - rest.trim_start().len();
+        if ws_len > 0 {
+            pending_ws.push_str(&rest[..ws_len]);
+            rest = &rest[ws_len..];
+            continue;
+        }
+        let word_len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let word = &rest[..word_len];
+        rest = &rest[word_len..];
+        if let Some(text) = decode_word(word) {
+            if !last_was_encoded {
+                decoded.push_str(&pending_ws);
+            }
+            run.push_str(&text);
+            last_was_encoded = true;
+        } else {
+            flush_run(&mut decoded, &mut run);
+            decoded.push_str(&pending_ws);
+            decoded.push_str(word);
+            last_was_encoded = false;
+        }
+        pending_ws.clear();
+    }
+    flush_run(&mut decoded, &mut run);
+    decoded.push_str(&pending_ws);
+    decoded
+}
+
+fn decode_word(word: &str) -> Option<String> {
+    let inner = word.strip_prefix("=?")?.strip_suffix("?=")?;
+    let mut pieces = inner.splitn(3, '?');
+    let charset = pieces.next()?;
+    // RFC 2231 allows a language suffix:  utf-8*en.
+    let charset = charset.split('*').next().unwrap_or(charset);
+    let encoding = pieces.next()?;
+    let text = pieces.next()?;
+    let bytes = match encoding {
+        "B" | "b" => decode_base64(text.as_bytes()),
+        "Q" | "q" => decode_quoted_printable(text.replace('_', " ").as_bytes()),
+        _ => return None,
+    };
+    Some(decode_charset(&bytes, Some(charset)))
+}
+
+////////////////////////////////////////////// encoding ////////////////////////////////////////////
+
+/// Encode an unstructured header such as Subject so that it is pure ASCII.
+pub fn encode(s: &str) -> String {
+    if s.is_ascii() {
+        return s.to_string();
+    }
+    let mut encoded = String::new();
+    let mut run = String::new();
+    for (ws, word) in words(s) {
+        if word.is_ascii() {
+            if !run.is_empty() {
+                encoded.push_str(&encode_phrase(&run));
+                run.clear();
+            }
+            encoded.push_str(ws);
+            encoded.push_str(word);
+        } else if run.is_empty() {
+            encoded.push_str(ws);
+            run.push_str(word);
+        } else {
+            run.push(' ');
+            run.push_str(word);
+        }
+    }
+    if !run.is_empty() {
+        encoded.push_str(&encode_phrase(&run));
+    }
+    encoded
+}
+
+/// Encode text as one or more encoded words suitable for use as a phrase, e.g. a display name.
+pub fn encode_phrase(s: &str) -> String {
+    let mut words = vec![];
+    let mut current = String::new();
+    let prefix = "=?utf-8?q?";
+    let suffix = "?=";
+    for c in s.chars() {
+        let mut buf = [0u8; 4];
+        let mut piece = String::new();
+        if c == ' ' {
+            piece.push('_');
+        } else if c.is_ascii_alphanumeric() || "!*+-/".contains(c) {
+            piece.push(c);
+        } else {
+            for b in c.encode_utf8(&mut buf).bytes() {
+                piece += &format!("={:02X}", b);
+            }
+        }
+        if prefix.len() + current.len() + piece.len() + suffix.len() > MAX_ENCODED_WORD {
+            words.push(format!("{}{}{}", prefix, current, suffix));
+            current.clear();
+        }
+        current.push_str(&piece);
+    }
+    if !current.is_empty() || words.is_empty() {
+        words.push(format!("{}{}{}", prefix, current, suffix));
+    }
+    words.join(" ")
+}
+
+/////////////////////////////////////////////// utils //////////////////////////////////////////////
+
+fn words(s: &str) -> Vec<(&str, &str)> {
+    let mut words = vec![];
+    let mut rest = s;
+    while !rest.is_empty() {
+        let ws_len = rest.len() - rest.trim_start().len();
+        let (ws, after) = rest.split_at(ws_len);
+        let word_len = after.find(char::is_whitespace).unwrap_or(after.len());
+        let (word, after) = after.split_at(word_len);
+        words.push((ws, word));
+        rest = after;
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_b_and_q_words() {
+        assert_eq!("caf\u{e9}", decode("=?UTF-8?B?Y2Fmw6k=?="));
+        assert_eq!(
+            "caf\u{e9} cr\u{e8}me",
+            decode("=?iso-8859-1?q?caf=E9_cr=E8me?=")
+        );
+    }
+
+    #[test]
+    fn drops_whitespace_between_adjacent_words_only() {
+        assert_eq!("ab", decode("=?utf-8?q?a?= \r\n =?utf-8?q?b?="));
+        assert_eq!("Re: a b", decode("Re: =?utf-8?q?a?= b"));
+    }
+
+    #[test]
+    fn leaves_malformed_words_alone() {
+        assert_eq!("=?utf-8?x?abc?=", decode("=?utf-8?x?abc?="));
+        assert_eq!("plain text", decode("plain text"));
+    }
+
+    #[test]
+    fn quotes_decoded_phrases_with_specials() {
+        assert_eq!(
+            "\"Doe, J\u{f6}hn\" <john@example.org>",
+            decode_structured("=?utf-8?q?Doe=2C_J=C3=B6hn?= <john@example.org>")
+        );
+    }
+
+    #[test]
+    fn ascii_is_not_encoded() {
+        assert_eq!("hello world", encode("hello world"));
+    }
+
+    #[test]
+    fn encodes_only_non_ascii_words() {
+        let encoded = encode("Re: caf\u{e9} time");
+        assert!(encoded.is_ascii());
+        assert!(encoded.starts_with("Re: =?utf-8?q?"));
+        assert!(encoded.ends_with(" time"));
+        assert_eq!("Re: caf\u{e9} time", decode(&encoded));
+    }
+
+    #[test]
+    fn long_text_splits_into_short_words_that_round_trip() {
+        let text = "\u{6f22}\u{5b57}".repeat(80);
+        let encoded = encode(&text);
+        assert!(encoded.is_ascii());
+        assert!(encoded
+            .split(' ')
+            .all(|word| word.len() <= MAX_ENCODED_WORD));
+        assert_eq!(text, decode(&encoded));
+    }
+}
@@ -0,0 +1,294 @@
+//! Limit how many requests the backend sees at once, overall and per model, and decide which waiting
+//! request goes next:  the most urgent by X-Priority, and the oldest among equals.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::sync::Notify;
+
+///////////////////////////////////////////// Scheduler ////////////////////////////////////////////
+
+/// Hands out permits to make requests.  Clones share the same limits and queue.
+#[derive(Clone, Debug)]
+pub struct Scheduler {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    queue: Mutex<Queue>,
+    changed: Notify,
+}
+
+#[derive(Debug)]
+struct Queue {
+    limit: usize,
+    per_model: usize,
+    next: u64,
+    // Keyed by priority and then arrival, so iteration visits waiters in the order they are served.
+    waiting: BTreeMap<(u8, u64), String>,
+    running: usize,
+    running_per_model: HashMap<String, usize>,
+    closed: bool,
+}
+
+impl Scheduler {
+    /// A scheduler that allows `limit` requests at once, no more than `per_model` of them to the
+    /// same model.  Limits below one are taken to be one.
+    pub fn new(limit: usize, per_model: usize) -> Self {
+        let queue = Queue {
+            limit: limit.max(1),
+            per_model: per_model.max(1),
+            next: 0,
+            waiting: BTreeMap::new(),
+            running: 0,
+            running_per_model: HashMap::new(),
+            closed: false,
+        };
+        Self {
+            inner: Arc::new(Inner {
+                queue: Mutex::new(queue),
+                changed: Notify::new(),
+            }),
+        }
+    }
+
+    /// Change the limits.  Requests already running are unaffected.
+    pub fn set_limits(&self, limit: usize, per_model: usize) {
+        let mut queue = self.queue();
+        if (queue.limit, queue.per_model) != (limit.max(1), per_model.max(1)) {
+            queue.limit = limit.max(1);
+            queue.per_model = per_model.max(1);
+            self.inner.changed.notify_waiters();
+        }
+    }
+
+    /// Stop handing out turns, e.g. to shut down.  Requests waiting for a turn, and any that ask
+    /// later, get none.
+    pub fn close(&self) {
+        self.queue().closed = true;
+        self.inner.changed.notify_waiters();
+    }
+
+    /// Wait for a turn to make a request to `model`.  Lower `priority` goes first, as in X-Priority.
+    /// The turn lasts until the permit is dropped.  Returns None once the scheduler is closed.
+    pub async fn acquire(&self, model: &str, priority: u8) -> Option<Permit> {
+        let key = {
+            let mut queue = self.queue();
+            let key = (priority, queue.next);
+            queue.next += 1;
+            queue.waiting.insert(key, model.to_string());
+            key
+        };
+        // Leave the queue if the caller gives up waiting.
+        let mut waiter = Waiter {
+            scheduler: self,
+            key: Some(key),
+        };
+        loop {
+            let changed = self.inner.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            {
+                let mut queue = self.queue();
+                if queue.closed {
+                    return None;
+                }
+                if queue.admits(key) {
+                    queue.waiting.remove(&key);
+                    queue.running += 1;
+                    *queue
+                        .running_per_model
+                        .entry(model.to_string())
+                        .or_default() += 1;
+                    waiter.key = None;
+                    return Some(Permit {
+                        scheduler: self.clone(),
+                        model: model.to_string(),
+                        priority,
+                    });
+                }
+            }
+            changed.await;
+        }
+    }
+
+    fn queue(&self) -> MutexGuard<'_, Queue> {
+        self.inner
+            .queue
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Queue {
+    // True if the waiter at `key` may run now.  Waiters ahead of it are served first, but only
+    // those whose model has room, so a busy model never holds up the others.
+    fn admits(&self, key: (u8, u64)) -> bool {
+        let mut free = self.limit.saturating_sub(self.running);
+        let mut ahead = HashMap::<&str, usize>::new();
+        for (waiter, model) in self.waiting.iter() {
+            if free == 0 {
+                return false;
+            }
+            let busy = self.running_per_model.get(model).copied().unwrap_or(0)
+                + ahead.get(model.as_str()).copied().unwrap_or(0);
+            let fits = busy < self.per_model;
+            if *waiter == key {
+                return fits;
+            }
+            if fits {
+                free -= 1;
+                *ahead.entry(model).or_default() += 1;
+            }
+        }
+        false
+    }
+}
+
+////////////////////////////////////////////// Permit //////////////////////////////////////////////
+
+/// A turn to make a request.  Dropping it lets the next request go.
+#[derive(Debug)]
+pub struct Permit {
+    scheduler: Scheduler,
+    model: String,
+    priority: u8,
+}
+
+impl Permit {
+    /// Give up the turn for `duration`, e.g. to back off before a retry, and then wait for another
+    /// turn at the same priority.  Returns None if the scheduler closes in the meantime.
+    pub async fn pause(self, duration: std::time::Duration) -> Option<Permit> {
+        let scheduler = self.scheduler.clone();
+        let model = self.model.clone();
+        let priority = self.priority;
+        drop(self);
+        tokio::time::sleep(duration).await;
+        scheduler.acquire(&model, priority).await
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut queue = self.scheduler.queue();
+        queue.running -= 1;
+        if let Some(running) = queue.running_per_model.get_mut(&self.model) {
+            *running -= 1;
+            if *running == 0 {
+                queue.running_per_model.remove(&self.model);
+            }
+        }
+        self.scheduler.inner.changed.notify_waiters();
+    }
+}
+
+// Removes an abandoned waiter from the queue.
+struct Waiter<'a> {
+    scheduler: &'a Scheduler,
+    key: Option<(u8, u64)>,
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.scheduler.queue().waiting.remove(&key);
+            self.scheduler.inner.changed.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    // Yield until `n` requests are waiting, so that tasks are queued in a known order.
+    async fn until_waiting(scheduler: &Scheduler, n: usize) {
+        while scheduler.queue().waiting.len() < n {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn busy_model_does_not_hold_up_others() {
+        let scheduler = Scheduler::new(2, 1);
+        let _first = scheduler.acquire("a", 3).await.unwrap();
+        let blocked = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move { scheduler.acquire("a", 3).await.is_some() }
+        });
+        until_waiting(&scheduler, 1).await;
+        let other = tokio::time::timeout(Duration::from_secs(5), scheduler.acquire("b", 3)).await;
+        assert!(other.unwrap().is_some());
+        assert!(!blocked.is_finished());
+        blocked.abort();
+    }
+
+    #[tokio::test]
+    async fn most_urgent_goes_first_then_oldest() {
+        let scheduler = Scheduler::new(1, 1);
+        let first = scheduler.acquire("m", 3).await.unwrap();
+        let order = Arc::new(Mutex::new(vec![]));
+        let mut tasks = vec![];
+        for (n, (model, priority)) in [("x", 3), ("y", 5), ("z", 1), ("w", 3)]
+            .into_iter()
+            .enumerate()
+        {
+            let order = Arc::clone(&order);
+            tasks.push(tokio::spawn({
+                let scheduler = scheduler.clone();
+                async move {
+                    let _permit = scheduler.acquire(model, priority).await.unwrap();
+                    order.lock().unwrap().push(model);
+                }
+            }));
+            until_waiting(&scheduler, n + 1).await;
+        }
+        drop(first);
+        for task in tasks.into_iter() {
+            task.await.unwrap();
+        }
+        assert_eq!(vec!["z", "x", "w", "y"], *order.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn close_releases_waiters() {
+        let scheduler = Scheduler::new(1, 1);
+        let _first = scheduler.acquire("a", 3).await.unwrap();
+        let waiting = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move { scheduler.acquire("b", 3).await.is_some() }
+        });
+        until_waiting(&scheduler, 1).await;
+        scheduler.close();
+        assert!(!waiting.await.unwrap());
+        assert!(scheduler.acquire("c", 3).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn abandoned_waiters_leave_the_queue() {
+        let scheduler = Scheduler::new(1, 1);
+        let first = scheduler.acquire("a", 3).await.unwrap();
+        let abandoned =
+            tokio::time::timeout(Duration::from_millis(10), scheduler.acquire("b", 1)).await;
+        assert!(abandoned.is_err());
+        assert!(scheduler.queue().waiting.is_empty());
+        drop(first);
+        let next = tokio::time::timeout(Duration::from_secs(5), scheduler.acquire("c", 3)).await;
+        assert!(next.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn pausing_gives_up_the_turn() {
+        let scheduler = Scheduler::new(1, 1);
+        let first = scheduler.acquire("a", 3).await.unwrap();
+        let paused = tokio::spawn(first.pause(Duration::from_millis(100)));
+        let other = tokio::time::timeout(Duration::from_secs(5), scheduler.acquire("b", 3)).await;
+        let other = other.unwrap().unwrap();
+        assert!(!paused.is_finished());
+        drop(other);
+        assert!(paused.await.unwrap().is_some());
+    }
+}
@@ -0,0 +1,217 @@
+//! Reconstruct the thread a message belongs to by following In-Reply-To and References.
+
+use std::collections::{HashMap, HashSet};
+
+use utf8path::Path;
+
+use super::mime::{parse_headers, read_headers};
+use super::{Header, ARCHIVE, CUR, INBOX, NEW, SENT};
+
+/////////////////////////////////////////////// Thread /////////////////////////////////////////////
+
+/// The ancestors of a message, oldest first.
+#[derive(Clone, Debug, Default)]
+pub struct Thread {
+    ancestors: Vec<Vec<u8>>,
+}
+
+impl Thread {
+    /// Rebuild the thread of `message` from the messages in INBOX, Sent, and Archive.  This reads
+    /// the headers of every message there, so call it off the async executor.
+    pub fn reconstruct(
+        knowledge_base: &Path<'_>,
+        message: impl AsRef<[u8]>,
+    ) -> Result<Self, std::io::Error> {
+        let index = index_message_ids(knowledge_base)?;
+        let mut ancestors = vec![];
+        let mut seen = HashSet::new();
+        let mut headers = parse_headers(message.as_ref());
+        if let Some(msg_id) = message_id(&headers) {
+            seen.insert(msg_id);
+        }
+        loop {
+            let mut parent = None;
+            for msg_id in parents(&headers) {
+                let Some(path) = index.get(&msg_id) else {
+                    continue;
+                };
+                if !seen.insert(msg_id) {
+                    continue;
+                }
+                // The user may file an ancestor away while it is read; the thread goes on without it.
+                if let Ok(ancestor) = std::fs::read(path) {
+                    parent = Some(ancestor);
+                    break;
+                }
+            }
+            let Some(ancestor) = parent else {
+                break;
+            };
+            headers = parse_headers(&ancestor);
+            let Some(msg_id) = message_id(&headers) else {
+                break;
+            };
+            seen.insert(msg_id);
+            ancestors.push(ancestor);
+        }
+        ancestors.reverse();
+        Ok(Self { ancestors })
+    }
+
+    /// The ancestors of the message, oldest first.
+    pub fn ancestors(&self) -> &[Vec<u8>] {
+        &self.ancestors
+    }
+}
+
+/////////////////////////////////////////////// utils //////////////////////////////////////////////
+
+fn message_id(headers: &[Header]) -> Option<String> {
+    headers.iter().find_map(|header| match header {
+        Header::MessageID(msg_id) => Some(msg_id.trim().to_string()),
+        _ => None,
+    })
+}
+
+// The candidate parents of a message, most likely first:  In-Reply-To, then References from the
+// most recent ancestor backwards so that a missing parent does not sever the thread.
+fn parents(headers: &[Header]) -> Vec<String> {
+    let mut parents = vec![];
+    for header in headers.iter() {
+        if let Header::InReplyTo(in_reply_to) = header {
+            parents.extend(split_msg_ids(in_reply_to));
+        }
+    }
+    for header in headers.iter() {
+        if let Header::References(references) = header {
+            parents.extend(split_msg_ids(references).into_iter().rev());
+        }
+    }
+    parents
+}
+
+/// The message IDs in a References or In-Reply-To header, in order.  Anything outside angle brackets,
+/// such as comments or phrases, is ignored.
+pub(crate) fn split_msg_ids(s: &str) -> Vec<String> {
+    let mut msg_ids = vec![];
+    let mut rest = s;
+    while let Some(start) = rest.find('<') {
+        let Some(len) = rest[start..].find('>') else {
+            break;
+        };
+        let msg_id = &rest[start..start + len + 1];
+        if !msg_id[1..].contains('<') && !msg_id.contains(char::is_whitespace) {
+            msg_ids.push(msg_id.to_string());
+        }
+        rest = &rest[start + len + 1..];
+    }
+    msg_ids
+}
+
+fn index_message_ids(
+    knowledge_base: &Path<'_>,
+) -> Result<HashMap<String, Path<'static>>, std::io::Error> {
+    let mut index = HashMap::new();
+    for level1 in &[INBOX, SENT, ARCHIVE] {
+        for level2 in &[CUR, NEW] {
+            let dir = knowledge_base.join(*level1).join(*level2);
+            if !dir.clone().into_std().is_dir() {
+                continue;
+            }
+            for dirent in std::fs::read_dir(&dir)? {
+                let dirent = dirent?;
+                let Ok(path) = Path::try_from(dirent.path()) else {
+                    continue;
+                };
+                if !path.clone().into_std().is_file() {
+                    continue;
+                }
+                let Ok(headers) = read_headers(&path) else {
+                    continue;
+                };
+                if let Some(msg_id) = message_id(&headers) {
+                    index.insert(msg_id, path.into_owned());
+                }
+            }
+        }
+    }
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{file_message, scratch_knowledge_base};
+
+    fn message(n: usize, parents: &[usize]) -> String {
+        let mut email = format!("Message-ID: <{}@example.org>\n", n);
+        if let Some(parent) = parents.last() {
+            email += &format!("In-Reply-To: <{}@example.org>\n", parent);
+            let references = parents
+                .iter()
+                .map(|n| format!("<{}@example.org>", n))
+                .collect::<Vec<_>>();
+            email += &format!("References: {}\n", references.join(" "));
+        }
+        email += &format!("\nMessage {}.\n", n);
+        email
+    }
+
+    fn bodies(thread: &Thread) -> Vec<String> {
+        thread
+            .ancestors()
+            .iter()
+            .map(|ancestor| {
+                let text = String::from_utf8_lossy(ancestor).into_owned();
+                text.rsplit("\n\n").next().unwrap().trim().to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ancestors_come_oldest_first_from_every_folder() {
+        let kb = scratch_knowledge_base("thread");
+        file_message(&kb, INBOX, "0.a:2,S", &message(0, &[]));
+        file_message(&kb, SENT, "1.a:2,S", &message(1, &[0]));
+        file_message(&kb, ARCHIVE, "2.a:2,S", &message(2, &[0, 1]));
+        // Unrelated mail is left out.
+        file_message(&kb, INBOX, "9.a:2,S", &message(9, &[0]));
+        let thread = Thread::reconstruct(&kb, message(3, &[0, 1, 2])).unwrap();
+        assert_eq!(
+            vec!["Message 0.", "Message 1.", "Message 2."],
+            bodies(&thread)
+        );
+        std::fs::remove_dir_all(&kb).unwrap();
+    }
+
+    #[test]
+    fn missing_ancestors_are_skipped() {
+        let kb = scratch_knowledge_base("gap");
+        file_message(&kb, INBOX, "0.a:2,S", &message(0, &[]));
+        file_message(&kb, INBOX, "2.a:2,S", &message(2, &[0, 1]));
+        let thread = Thread::reconstruct(&kb, message(3, &[0, 1, 2])).unwrap();
+        assert_eq!(vec!["Message 0.", "Message 2."], bodies(&thread));
+        // Nor does a thread whose parent is gone lose the rest.
+        let thread = Thread::reconstruct(&kb, message(4, &[0, 1, 3])).unwrap();
+        assert_eq!(vec!["Message 0."], bodies(&thread));
+        std::fs::remove_dir_all(&kb).unwrap();
+    }
+
+    #[test]
+    fn reference_cycles_end() {
+        let kb = scratch_knowledge_base("cycle");
+        file_message(&kb, INBOX, "0.a:2,S", &message(0, &[1]));
+        file_message(&kb, INBOX, "1.a:2,S", &message(1, &[0]));
+        let thread = Thread::reconstruct(&kb, message(2, &[1])).unwrap();
+        assert_eq!(vec!["Message 0.", "Message 1."], bodies(&thread));
+        std::fs::remove_dir_all(&kb).unwrap();
+    }
+
+    #[test]
+    fn message_ids_split_from_references() {
+        assert_eq!(
+            vec!["<a@x>", "<b@x>"],
+            split_msg_ids("(first) <a@x>\n <b@x> <not an id> trailing")
+        );
+    }
+}
@@ -0,0 +1,97 @@
+//! Wait for messages to arrive in, or leave, maildir folders.
+
+use std::time::Duration;
+
+use utf8path::Path;
+
+/// How often to look for new messages when inotify is unavailable.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+////////////////////////////////////////////// Watcher /////////////////////////////////////////////
+
+/// Watches directories for messages moved or written into them, or moved or deleted out of them.
+///
+/// On Linux this uses inotify, so waiting costs nothing and wakes within milliseconds of mutt saving
+/// a message.  Elsewhere, or if inotify cannot be set up, it polls once a second.  Either way,
+/// waiting never blocks the executor.
+pub struct Watcher {
+    backend: Backend,
+}
+
+enum Backend {
+    #[cfg(target_os = "linux")]
+    Inotify(tokio::sync::mpsc::Receiver<()>),
+    Poll(tokio::time::Interval),
+}
+
+impl Watcher {
+    /// Watch `dirs`.
+    pub fn new(dirs: &[Path<'_>]) -> Self {
+        #[cfg(target_os = "linux")]
+        match inotify_backend(dirs) {
+            Ok(backend) => return Self { backend },
+            Err(err) => {
+                eprintln!("watching by polling: {}", err);
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = dirs;
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        Self {
+            backend: Backend::Poll(interval),
+        }
+    }
+
+    /// Wait until a directory may have changed.  Bursts of changes are coalesced into one wakeup.
+    pub async fn changed(&mut self) {
+        match &mut self.backend {
+            #[cfg(target_os = "linux")]
+            Backend::Inotify(rx) => {
+                if rx.recv().await.is_none() {
+                    // The inotify thread died; fall back to polling rather than spinning.
+                    eprintln!("inotify stopped; watching by polling");
+                    let mut interval = tokio::time::interval(POLL_INTERVAL);
+                    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                    interval.tick().await;
+                    self.backend = Backend::Poll(interval);
+                }
+            }
+            Backend::Poll(interval) => {
+                interval.tick().await;
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn inotify_backend(dirs: &[Path<'_>]) -> Result<Backend, std::io::Error> {
+    use inotify::{Inotify, WatchMask};
+
+    let mut inotify = Inotify::init()?;
+    // mutt delivers by renaming out of tmp/; anything else that writes in place is seen on close.
+    // Messages leave by being renamed to another folder or deleted.
+    let mask =
+        WatchMask::MOVED_TO | WatchMask::CLOSE_WRITE | WatchMask::MOVED_FROM | WatchMask::DELETE;
+    for dir in dirs {
+        inotify
+            .watches()
+            .add(dir.as_str(), mask)
+            .map_err(|err| std::io::Error::new(err.kind(), format!("{}: {}", dir, err)))?;
+    }
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 4096];
+        loop {
+            if let Err(err) = inotify.read_events_blocking(&mut buffer) {
+                eprintln!("inotify: {}", err);
+                return;
+            }
+            match tx.try_send(()) {
+                Ok(()) | Err(tokio::sync::mpsc::error::TrySendError::Full(())) => {}
+                Err(tokio::sync::mpsc::error::TrySendError::Closed(())) => return,
+            }
+        }
+    });
+    Ok(Backend::Inotify(rx))
+}